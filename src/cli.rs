@@ -2,12 +2,17 @@
 
 use crate::config;
 use crate::downstream::HTML;
+use crate::fetch::{fetch_all, Job, Limits};
+use crate::http;
+use crate::lock::Lock;
 use crate::metadata;
-use crate::upstream::{to_source, Article};
-use crate::util::{self, get_metadata_dir, get_output_dir};
-use std::error::Error;
-use std::fs::{create_dir_all, write};
-use std::path::PathBuf;
+use crate::opml;
+use crate::retry;
+use crate::state::{self, StateStore};
+use crate::upstream::{self, Article, Source};
+use crate::util::{self, create_dir_all, get_metadata_dir, get_output_dir, write_atomic};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -17,6 +22,7 @@ use structopt::StructOpt;
     rename_all = "kebab-case"
 )]
 /// The options available by run `rsst`.
+#[allow(clippy::struct_excessive_bools)]
 pub struct Opt {
     #[structopt(short = "d", long)]
     /// Prints the list of path that articles will be write into
@@ -27,17 +33,64 @@ pub struct Opt {
     #[structopt(short = "-c", long, parse(from_os_str))]
     /// Loads configuration file at the path
     pub config: Option<PathBuf>,
+    #[structopt(long)]
+    /// Backs up a corrupt state file and salvages the entries still readable
+    pub repair: bool,
+    #[structopt(long, conflicts_with = "no-wait")]
+    /// Waits for another running instance to finish instead of exiting
+    pub wait: bool,
+    #[structopt(long)]
+    /// Exits at once if another instance is running (the default)
+    pub no_wait: bool,
+    #[structopt(short = "f", long)]
+    /// Retrieves every source, even those not due yet
+    pub force: bool,
+    #[structopt(short = "g", long, number_of_values = 1)]
+    /// Only handles the sources in the group, or tagged with it; can be repeated
+    pub group: Vec<String>,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
-/// Try parse the file at `PathBuf` `p` into `metadata::Collection`.
-fn get_collection(p: PathBuf) -> Result<metadata::Collection, util::Error> {
-    match metadata::get(p) {
-        Ok(v) => Ok(v),
-        Err(util::Error::NotFound) | Err(util::Error::ParseFailed) => Ok(metadata::Collection {
-            ..metadata::Collection::default()
-        }),
-        Err(e) => Err(e),
-    }
+/// The commands that do something else than retrieving the feeds.
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Command {
+    #[structopt(name = "import")]
+    /// Adds the feeds listed in a file to the config file
+    Import(Import),
+    #[structopt(name = "export")]
+    /// Writes the followed feeds into a file other readers can import
+    Export(Export),
+    #[structopt(name = "check-config")]
+    /// Prints every problem found in the config file
+    CheckConfig,
+}
+
+/// The formats feeds can be imported from.
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Import {
+    #[structopt(name = "opml")]
+    /// Imports an OPML subscription list, taking nested outlines as groups
+    Opml {
+        #[structopt(parse(from_os_str))]
+        /// The OPML file to import
+        file: PathBuf,
+    },
+}
+
+/// The formats feeds can be exported to.
+#[derive(StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+pub enum Export {
+    #[structopt(name = "opml")]
+    /// Exports an OPML subscription list, nesting the feeds by group
+    Opml {
+        #[structopt(short = "o", long, parse(from_os_str))]
+        /// Writes the list into the file at the path instead of stdout
+        output: Option<PathBuf>,
+    },
 }
 
 /// Gets the exclusive upperbound index that marks the last article should dump.
@@ -51,58 +104,912 @@ fn get_bound(metadata: Option<metadata::Metadata>, article: &[Article]) -> usize
     }
 }
 
-/// Run the given command in `opt`. Return an `Error` if failed at any point.
-pub fn run(opt: Opt) -> Result<(), Box<dyn Error>> {
-    let config = config::get(opt.config)?;
-    let metadata_dir = get_metadata_dir(config.setting.metadata_dir)?;
-    create_dir_all(&metadata_dir)?;
-    let mut collection = get_collection(metadata_dir.join("collections.json"))?;
-    let output_dir: std::path::PathBuf = get_output_dir(config.setting.output_dir)?;
+/// The status `rsst` exits with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// every source was dumped.
+    Success = 0,
+    /// the run stopped because of an error not covered below.
+    Failure = 1,
+    /// some sources could not be dumped; the others were.
+    PartialFailure = 2,
+    /// the config file is missing or invalid.
+    ConfigError = 3,
+    /// no source could be retrieved, e.g. because the network is down.
+    NetworkFailure = 4,
+    /// the state of the feeds could not be read or written, or another
+    /// instance holds the run lock.
+    StateError = 5,
+}
+
+/// An error that stopped the whole run.
+#[derive(Debug)]
+pub struct Fatal {
+    /// the status to exit with.
+    pub status: Status,
+    /// what went wrong.
+    pub error: util::Error,
+}
+
+impl Fatal {
+    /// Wraps an `error` about the config file.
+    fn config(error: util::Error) -> Self {
+        Self {
+            status: Status::ConfigError,
+            error,
+        }
+    }
+
+    /// Wraps an `error` about the state of the feeds.
+    fn state(error: util::Error) -> Self {
+        Self {
+            status: Status::StateError,
+            error,
+        }
+    }
+}
+
+impl From<util::Error> for Fatal {
+    fn from(error: util::Error) -> Self {
+        Self {
+            status: Status::Failure,
+            error,
+        }
+    }
+}
+
+impl std::fmt::Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+/// A source that could not be dumped.
+#[derive(Debug)]
+pub struct Failure {
+    /// the alias of the source.
+    pub alias: String,
+    /// the address of the source.
+    pub url: String,
+    /// what went wrong.
+    pub cause: util::Error,
+}
+
+impl Failure {
+    /// Returns what went wrong, leaving out `url` when `cause` repeats it.
+    fn reason(&self) -> String {
+        let at_url = |path: &Path| path == Path::new(&self.url);
+        match &self.cause {
+            util::Error::FetchFailed { url, source } if *url == self.url => source.to_string(),
+            util::Error::Http { url, status, .. } if *url == self.url => {
+                format!("the server answered {status}")
+            }
+            util::Error::FeedParseFailed { url, source } if *url == self.url => {
+                format!("not a valid feed: {source}")
+            }
+            util::Error::NotFound { path } if at_url(path) => String::from("no such file"),
+            util::Error::Io { path, source } if at_url(path) => source.to_string(),
+            cause => cause.to_string(),
+        }
+    }
+}
+
+/// What a run has done.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// the number of sources attempted.
+    pub attempted: usize,
+    /// the sources that could not be dumped.
+    pub failures: Vec<Failure>,
+}
+
+impl Report {
+    /// Returns the status a run that ended with `self` should exit with.
+    #[must_use]
+    pub fn status(&self) -> Status {
+        let network = |f: &Failure| {
+            matches!(
+                f.cause,
+                util::Error::FetchFailed { .. } | util::Error::Http { .. }
+            )
+        };
+        if self.failures.is_empty() {
+            Status::Success
+        } else if self.failures.len() == self.attempted && self.failures.iter().all(network) {
+            Status::NetworkFailure
+        } else {
+            Status::PartialFailure
+        }
+    }
+
+    /// Prints a table of the failed sources to stderr, if there is any.
+    pub fn print_summary(&self) {
+        if self.failures.is_empty() {
+            return;
+        }
+        let causes: Vec<_> = self.failures.iter().map(Failure::reason).collect();
+        let alias_width = self
+            .failures
+            .iter()
+            .map(|f| f.alias.len())
+            .chain(Some("ALIAS".len()))
+            .max()
+            .unwrap_or(0);
+        let url_width = self
+            .failures
+            .iter()
+            .map(|f| f.url.len())
+            .chain(Some("URL".len()))
+            .max()
+            .unwrap_or(0);
+        eprintln!(
+            "\n{} of {} source(s) failed:",
+            self.failures.len(),
+            self.attempted
+        );
+        eprintln!(
+            "{:a$}  {:u$}  CAUSE",
+            "ALIAS",
+            "URL",
+            a = alias_width,
+            u = url_width
+        );
+        for (f, cause) in self.failures.iter().zip(causes) {
+            eprintln!(
+                "{:a$}  {:u$}  {}",
+                f.alias,
+                f.url,
+                cause,
+                a = alias_width,
+                u = url_width
+            );
+        }
+    }
+}
+
+/// Dumps the new articles of `source`, retrieved from `url` and known as
+/// `alias`, into `dir`, recording its new state in `store`. Returns the
+/// number of articles dumped, or the error that stopped writing them.
+/// Fails if `store` can't be read or written, which concerns every source.
+fn dump(
+    opt: &Opt,
+    store: &mut dyn StateStore,
+    alias: &str,
+    url: &str,
+    dir: &Path,
+    output_format: &str,
+    source: Source,
+) -> Result<Result<usize, util::Error>, Fatal> {
+    if let Err(e) = create_dir_all(dir) {
+        return Ok(Err(e));
+    }
+    let previous = store.metadata(alias).map_err(Fatal::state)?;
+    // the feed counts as retrieved only once all its articles are dumped.
+    let fetched = previous.as_ref().and_then(|m| m.fetched);
+    let bound = get_bound(previous, &source.article);
+    let turn_into = if output_format == "html" {
+        HTML::from
+    } else {
+        return Ok(Err(util::Error::NotSupported {
+            what: format!("output format `{output_format}`"),
+        }));
+    };
+    let output: Vec<_> = source.article[..bound]
+        .iter()
+        .map(|a| (a, turn_into(a)))
+        .collect();
+    if opt.stdout {
+        println!("{:?}", source.metadata);
+        for (_, o) in output {
+            println!("{}", o.to_string());
+        }
+    } else {
+        for (a, o) in output.iter().rev() {
+            let filepath = dir.join(o.filename());
+            println!("dumping {} ...", filepath.to_str().unwrap());
+            if let Err(e) = write_atomic(&filepath, o.to_string().as_bytes()) {
+                return Ok(Err(e));
+            }
+            store
+                .record_article(alias, &a.checksum, &filepath)
+                .map_err(Fatal::state)?;
+            // articles are dumped from the oldest one, so a run
+            // interrupted here resumes right after `a`.
+            store
+                .set_metadata(
+                    alias,
+                    url,
+                    metadata::Metadata {
+                        title: String::clone(&source.metadata.title),
+                        checksum: String::clone(&a.checksum),
+                        fetched,
+                        schedule: source.metadata.schedule.clone(),
+                    },
+                )
+                .map_err(Fatal::state)?;
+            store.commit().map_err(Fatal::state)?;
+        }
+    }
+    store
+        .set_metadata(alias, url, source.metadata)
+        .map_err(Fatal::state)?;
+    // only now that every article is dumped may the server skip them.
+    store
+        .set_validators(alias, source.validators)
+        .map_err(Fatal::state)?;
+    store.commit().map_err(Fatal::state)?;
+    Ok(Ok(bound))
+}
+
+/// Records in `store` that the feed at `url`, known as `alias`, was just
+/// retrieved and has not changed since last time.
+fn unchanged(store: &mut dyn StateStore, alias: &str, url: &str) -> Result<(), Fatal> {
+    println!("{alias} has not changed since last time");
+    let Some(mut metadata) = store.metadata(alias).map_err(Fatal::state)? else {
+        return Ok(());
+    };
+    metadata.fetched = Some(util::now());
+    store
+        .set_metadata(alias, url, metadata)
+        .map_err(Fatal::state)?;
+    store.commit().map_err(Fatal::state)
+}
+
+/// Prints where the articles of each of `sources` would be written, creating
+/// the directories on the way.
+fn dry_run<'a>(
+    output_dir: &Path,
+    nest_by_group: bool,
+    sources: impl Iterator<Item = (&'a String, &'a config::Source)>,
+) -> Result<(), Fatal> {
+    for (alias, source) in sources {
+        let dir = output_dir_of(output_dir, nest_by_group, alias, source)?;
+        create_dir_all(&dir)?;
+        println!(
+            "{} -> {}",
+            dir.to_str().expect("failed to convert to path"),
+            source.url
+        );
+    }
+    Ok(())
+}
+
+/// Returns the jobs retrieving those of `sources` that are due, or all of
+/// them with `--force`. The sources whose job could not be made are added
+/// to `report` as failures.
+fn jobs<'a>(
+    opt: &Opt,
+    config: &config::Config,
+    store: &dyn StateStore,
+    sources: impl Iterator<Item = (&'a String, &'a config::Source)>,
+    report: &mut Report,
+) -> Result<Vec<Job>, Fatal> {
+    let mut jobs = vec![];
+    let now = util::now();
+    for (alias, source) in sources {
+        if !opt.force {
+            let metadata = store.metadata(alias).map_err(Fatal::state)?;
+            if let Some(metadata) = metadata {
+                let min_interval = source.min_interval.or(config.setting.min_interval);
+                let due = metadata.fetched.map(|fetched| {
+                    metadata
+                        .schedule
+                        .next_due(fetched, min_interval.unwrap_or(0).saturating_mul(60))
+                });
+                if let Some(due) = due.filter(|&due| due > now) {
+                    println!(
+                        "{} is not due for another {} minute(s)",
+                        alias,
+                        (due - now + 59) / 60
+                    );
+                    continue;
+                }
+            }
+        }
+        let url = upstream::normalize(&source.url);
+        let options = headers(alias, source).map(|headers| http::Options {
+            headers,
+            proxy: source.proxy.clone(),
+            global_proxy: config.setting.proxy.clone(),
+        });
+        let options = match options {
+            Ok(v) => v,
+            Err(cause) => {
+                eprintln!("failed to dump {alias}: {cause}");
+                report.attempted += 1;
+                report.failures.push(Failure {
+                    alias: String::clone(alias),
+                    url,
+                    cause,
+                });
+                continue;
+            }
+        };
+        jobs.push(Job {
+            alias: String::clone(alias),
+            url,
+            validators: store.validators(alias).map_err(Fatal::state)?,
+            retry: retry_policy(&config.setting, source),
+            options,
+            filter: source.filter.clone(),
+        });
+    }
+    Ok(jobs)
+}
+
+/// Adds the feeds listed in the OPML file at `file` to the config file at
+/// `config_path`.
+fn import_opml(config_path: &Path, file: &Path) -> Result<Report, Fatal> {
+    let text = util::to_string(file)?;
+    let feeds = opml::parse(file, &text)?;
+    let imported = config::import(config_path, &feeds).map_err(Fatal::config)?;
+    for (alias, url) in &imported.added {
+        println!("{alias} -> {url}");
+    }
+    for url in &imported.skipped {
+        println!("skipped {url}: already followed");
+    }
+    for url in &imported.rejected {
+        println!("skipped {url}: not an http(s) address");
+    }
+    println!(
+        "imported {} of {} feed(s) into {}",
+        imported.added.len(),
+        feeds.len(),
+        config_path.display()
+    );
+    Ok(Report::default())
+}
+
+/// Writes the feeds followed in the config file at `config_path` as an OPML
+/// subscription list into `output`, or stdout, keeping those in the groups
+/// given in `opt`. Feeds are titled as they were when last retrieved, or by
+/// their alias.
+fn export_opml(opt: &Opt, config_path: &Path, output: Option<&Path>) -> Result<Report, Fatal> {
+    let config = config::get(Some(PathBuf::from(config_path))).map_err(Fatal::config)?;
+    let metadata_dir =
+        get_metadata_dir(config.setting.metadata_dir.clone()).map_err(Fatal::state)?;
+    // without a metadata directory, no feed was retrieved yet and the
+    // aliases stand for the titles.
+    let (_lock, store) = if metadata_dir.is_dir() {
+        let lock = Lock::acquire(&metadata_dir, opt.wait && !opt.no_wait).map_err(Fatal::state)?;
+        let store = state::open(
+            config.setting.state_backend.as_deref(),
+            &metadata_dir,
+            false,
+        )
+        .map_err(Fatal::state)?;
+        (Some(lock), Some(store))
+    } else {
+        (None, None)
+    };
+    let mut feeds = vec![];
+    let sources = config.source.iter().filter(|(_, s)| selected(opt, s));
+    for (alias, source) in sources {
+        if !upstream::is_remote(&source.url) {
+            eprintln!(
+                "note: {} is not exported: {} is not on the network",
+                alias, source.url
+            );
+            continue;
+        }
+        let metadata = match &store {
+            Some(store) => store.metadata(alias).map_err(Fatal::state)?,
+            None => None,
+        };
+        let title = metadata
+            .map(|m| m.title)
+            .filter(|t| !t.is_empty())
+            .unwrap_or_else(|| String::clone(alias));
+        feeds.push(opml::Feed {
+            title,
+            url: upstream::normalize(&source.url),
+            group: source.group.clone(),
+        });
+    }
+    let opml = opml::write("rsst", &feeds);
+    match output {
+        Some(path) => {
+            write_atomic(path, opml.as_bytes())?;
+            eprintln!("exported {} feed(s) into {}", feeds.len(), path.display());
+        }
+        None => print!("{opml}"),
+    }
+    Ok(Report::default())
+}
+
+/// Checks the config file at `config_path`, whose problems are all listed
+/// in the returned error if there is any.
+fn check_config(config_path: &Path) -> Result<Report, Fatal> {
+    let config = config::get(Some(PathBuf::from(config_path))).map_err(Fatal::config)?;
+    println!(
+        "{}: {} source(s), no problems found",
+        config_path.display(),
+        config.source.len()
+    );
+    Ok(Report::default())
+}
+
+/// Tells that `url`, the address of `alias`, is a web page linking to the
+/// feeds `discovered`, the first of which was read.
+fn discovered(alias: &str, url: &str, discovered: &[String]) {
+    if let Some((feed, others)) = discovered.split_first() {
+        eprintln!(
+            "note: {alias}: {url} is a web page; read the feed it links to, {feed}. Put it in the config to skip this step."
+        );
+        if !others.is_empty() {
+            eprintln!("note: {}: it also links to {}", alias, others.join(", "));
+        }
+    }
+}
+
+/// Returns how many feeds `setting` allows to retrieve at the same time.
+fn limits(setting: &config::Setting) -> Limits {
+    let defaults = Limits::default();
+    Limits {
+        total: setting.concurrency.unwrap_or(defaults.total),
+        per_host: setting.per_host_concurrency.unwrap_or(defaults.per_host),
+    }
+}
+
+/// Returns the HTTP client configured by `setting`.
+fn client(setting: &config::Setting) -> http::Client {
+    let defaults = http::Client::default();
+    http::Client {
+        connect_timeout: setting
+            .connect_timeout
+            .map_or(defaults.connect_timeout, Duration::from_secs),
+        read_timeout: setting
+            .read_timeout
+            .map_or(defaults.read_timeout, Duration::from_secs),
+        max_redirects: setting.max_redirects.unwrap_or(defaults.max_redirects),
+        user_agent: setting.user_agent.clone().unwrap_or(defaults.user_agent),
+    }
+}
+
+/// Warns that the source `alias` has permanently moved from `from` to `to`,
+/// and updates it in the config file at `config_path` if `rewrite` is set.
+fn moved(config_path: &Path, rewrite: bool, alias: &str, from: &str, to: &str) {
+    if !rewrite {
+        eprintln!(
+            "warning: {alias} has permanently moved from {from} to {to}; update the config or set `rewrite_redirects = true`"
+        );
+        return;
+    }
+    match config::rewrite_url(config_path, from, to) {
+        Ok(true) => eprintln!(
+            "{} has permanently moved from {} to {}; updated {}",
+            alias,
+            from,
+            to,
+            config_path.display()
+        ),
+        Ok(false) => eprintln!(
+            "warning: {} has permanently moved from {} to {}, but it could not be found in {}",
+            alias,
+            from,
+            to,
+            config_path.display()
+        ),
+        Err(e) => eprintln!(
+            "warning: {alias} has permanently moved from {from} to {to}, but the config could not be updated: {e}"
+        ),
+    }
+}
+
+/// Returns the directory the articles of `source`, known as `alias`, are
+/// dumped into: its own `output_dir`, or else `output_dir`, joined with a
+/// subdirectory per group it is nested in if `nest_by_group`, then with
+/// `alias`.
+fn output_dir_of(
+    output_dir: &Path,
+    nest_by_group: bool,
+    alias: &str,
+    source: &config::Source,
+) -> Result<PathBuf, util::Error> {
+    let mut dir = match &source.output_dir {
+        Some(dir) => util::expand(dir)?,
+        None => PathBuf::from(output_dir),
+    };
+    if nest_by_group {
+        let groups = source.group.iter().flat_map(|g| g.split('/'));
+        for group in groups.filter(|g| !["", ".", ".."].contains(&g.trim())) {
+            dir.push(group);
+        }
+    }
+    Ok(dir.join(alias))
+}
+
+/// Returns whether `source` is in one of the groups given in `opt`, if any.
+fn selected(opt: &Opt, source: &config::Source) -> bool {
+    opt.group.is_empty() || opt.group.iter().any(|g| source.in_group(g))
+}
+
+/// Returns how to retry `source`, which overrides `setting`.
+fn retry_policy(setting: &config::Setting, source: &config::Source) -> retry::Policy {
+    let defaults = retry::Policy::default();
+    retry::Policy {
+        retries: source
+            .retries
+            .or(setting.retries)
+            .unwrap_or(defaults.retries),
+        delay: source
+            .retry_delay
+            .or(setting.retry_delay)
+            .map_or(defaults.delay, Duration::from_secs),
+        max_delay: source
+            .max_retry_delay
+            .or(setting.max_retry_delay)
+            .map_or(defaults.max_delay, Duration::from_secs),
+    }
+}
+
+/// Returns the extra headers to send for `source`, known as `alias`, with
+/// its secrets read.
+fn headers(alias: &str, source: &config::Source) -> Result<Vec<(String, String)>, util::Error> {
+    let mut headers = vec![];
+    if let Some(user_agent) = &source.user_agent {
+        headers.push((String::from("User-Agent"), String::clone(user_agent)));
+    }
+    if let Some(auth) = &source.basic_auth {
+        let password = auth.password.resolve(&format!("the password of {alias}"))?;
+        headers.push((
+            String::from("Authorization"),
+            http::basic_auth(&auth.username, &password),
+        ));
+    }
+    if let Some(token) = &source.bearer_token {
+        let token = token.resolve(&format!("the bearer token of {alias}"))?;
+        headers.push((String::from("Authorization"), format!("Bearer {token}")));
+    }
+    for (name, value) in &source.headers {
+        let value = value.resolve(&format!("the {name} header of {alias}"))?;
+        headers.push((String::clone(name), value));
+    }
+    Ok(headers)
+}
+
+/// Run the given command in `opt`. Every source is attempted even if some of
+/// them fail; those are listed in the returned `Report`. Return a `Fatal`
+/// if failed at any point that concerns all of them.
+pub fn run(opt: &Opt) -> Result<Report, Fatal> {
+    let start = Instant::now();
+    let config_path = config::path(opt.config.clone()).map_err(Fatal::config)?;
+    match &opt.command {
+        Some(Command::Import(Import::Opml { file })) => return import_opml(&config_path, file),
+        Some(Command::Export(Export::Opml { output })) => {
+            return export_opml(opt, &config_path, output.as_deref())
+        }
+        Some(Command::CheckConfig) => return check_config(&config_path),
+        None => (),
+    }
+    let config = config::get(Some(PathBuf::clone(&config_path))).map_err(Fatal::config)?;
+    let metadata_dir =
+        get_metadata_dir(config.setting.metadata_dir.clone()).map_err(Fatal::state)?;
+    create_dir_all(&metadata_dir).map_err(Fatal::state)?;
+    let _lock = Lock::acquire(&metadata_dir, opt.wait && !opt.no_wait).map_err(Fatal::state)?;
+    let mut store = state::open(
+        config.setting.state_backend.as_deref(),
+        &metadata_dir,
+        opt.repair,
+    )
+    .map_err(Fatal::state)?;
+    let output_dir: std::path::PathBuf = get_output_dir(config.setting.output_dir.clone())?;
     create_dir_all(&output_dir)?;
-    let output_format = match config.setting.output_format {
+    let output_format = match config.setting.output_format.clone() {
         Some(v) => v,
         None => String::from("html"),
     };
-    for (alias, source) in &config.source {
-        let dir = output_dir.join(alias);
-        create_dir_all(&dir)?;
-        if opt.dry_run {
-            println!(
-                "{} -> {}",
-                dir.to_str().expect("failed to convert to path"),
-                source
-            );
-        } else {
-            let source = to_source(source)?;
-            let bound = get_bound(
-                collection
-                    .metadata
-                    .insert(String::clone(alias), source.metadata),
-                &source.article,
-            );
-            let turn_into = if output_format == "html" {
-                HTML::from
-            } else {
-                panic!("unsupported");
-            };
-            let output: Vec<_> = source.article[..bound]
-                .iter()
-                .map(|a| turn_into(a))
-                .collect();
-            if opt.stdout {
-                println!("{:?}", collection.metadata[alias]);
-                for o in output {
-                    println!("{}", o.to_string());
-                }
-            } else {
-                for o in output.iter().rev() {
-                    let filepath = dir.join(o.filename());
-                    println!("dumping {} ...", filepath.to_str().unwrap());
-                    write(filepath, o.to_string())?;
+    let mut report = Report::default();
+    let nest_by_group = config.setting.nest_by_group.unwrap_or(false);
+    let sources = config
+        .source
+        .iter()
+        .filter(|(_, source)| source.is_enabled() && selected(opt, source));
+    if opt.dry_run {
+        dry_run(&output_dir, nest_by_group, sources)?;
+        return Ok(report);
+    }
+    let jobs = jobs(opt, &config, &*store, sources, &mut report)?;
+    let limits = limits(&config.setting);
+    let client = client(&config.setting);
+    let rewrite_redirects = config.setting.rewrite_redirects.unwrap_or(false);
+    let deadline = start + Duration::from_secs(config.setting.max_run_time.unwrap_or(600));
+    fetch_all(
+        &client,
+        jobs,
+        limits,
+        Some(deadline),
+        |job, retrieved| -> Result<(), Fatal> {
+            let alias = &job.alias;
+            let source = &config.source[alias];
+            let dir = output_dir_of(&output_dir, nest_by_group, alias, source);
+            let output_format = source.format.as_deref().unwrap_or(&output_format);
+            report.attempted += 1;
+            let result = match retrieved {
+                Ok(retrieved) => {
+                    let url = match &retrieved.moved_to {
+                        Some(to) => {
+                            // the config may still have the address without a scheme.
+                            let from = &source.url;
+                            moved(&config_path, rewrite_redirects, alias, from, to);
+                            to
+                        }
+                        None => &job.url,
+                    };
+                    discovered(alias, url, &retrieved.discovered);
+                    match (retrieved.source, dir) {
+                        (Some(source), Ok(dir)) => {
+                            dump(opt, &mut *store, alias, url, &dir, output_format, source)?
+                        }
+                        (Some(_), Err(e)) => Err(e),
+                        (None, _) => {
+                            unchanged(&mut *store, alias, url)?;
+                            Ok(0)
+                        }
+                    }
                 }
+                Err(e) => Err(e),
+            };
+            store
+                .record_fetch(
+                    alias,
+                    &state::Fetch {
+                        timestamp: util::now(),
+                        articles: *result.as_ref().unwrap_or(&0),
+                        error: result.as_ref().err().map(ToString::to_string),
+                    },
+                )
+                .map_err(Fatal::state)?;
+            if let Err(cause) = result {
+                eprintln!("failed to dump {alias}: {cause}");
+                report.failures.push(Failure {
+                    alias: String::clone(alias),
+                    url: String::clone(&job.url),
+                    cause,
+                });
+            }
+            Ok(())
+        },
+    )?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::Schedule;
+    use std::fs;
+
+    /// A `StateStore` that fails to record the article whose checksum is
+    /// `crash_at`, as a run killed while dumping it would.
+    struct Crashing {
+        store: Box<dyn StateStore>,
+        crash_at: &'static str,
+    }
+
+    impl StateStore for Crashing {
+        fn metadata(&self, alias: &str) -> Result<Option<metadata::Metadata>, util::Error> {
+            self.store.metadata(alias)
+        }
+
+        fn set_metadata(
+            &mut self,
+            alias: &str,
+            url: &str,
+            metadata: metadata::Metadata,
+        ) -> Result<(), util::Error> {
+            self.store.set_metadata(alias, url, metadata)
+        }
+
+        fn validators(&self, alias: &str) -> Result<Option<metadata::Validators>, util::Error> {
+            self.store.validators(alias)
+        }
+
+        fn set_validators(
+            &mut self,
+            alias: &str,
+            validators: metadata::Validators,
+        ) -> Result<(), util::Error> {
+            self.store.set_validators(alias, validators)
+        }
+
+        fn record_article(
+            &mut self,
+            alias: &str,
+            checksum: &str,
+            path: &Path,
+        ) -> Result<(), util::Error> {
+            if checksum == self.crash_at {
+                return Err(util::Error::StoreFailed {
+                    path: PathBuf::from(path),
+                    source: "crashed".into(),
+                });
             }
+            self.store.record_article(alias, checksum, path)
+        }
+
+        fn commit(&mut self) -> Result<(), util::Error> {
+            self.store.commit()
         }
     }
-    write(metadata_dir.join("collections.json"), collection.put()?)?;
-    Ok(())
+
+    /// Returns an article titled and checksummed `name`.
+    fn article(name: &str) -> Article {
+        Article {
+            title: String::from(name),
+            link: format!("https://example.com/{name}"),
+            author: String::new(),
+            date: String::new(),
+            category: vec![],
+            content: format!("<p>{name}</p>"),
+            checksum: String::from(name),
+        }
+    }
+
+    fn failure(cause: util::Error) -> Failure {
+        Failure {
+            alias: String::from("example"),
+            url: String::from("https://example.com/rss.xml"),
+            cause,
+        }
+    }
+
+    fn fetch_failed() -> util::Error {
+        util::Error::Http {
+            url: String::from("https://example.com/rss.xml"),
+            status: 503,
+            retry_after: None,
+        }
+    }
+
+    #[test]
+    fn nested_output_dir() {
+        let source = config::Source {
+            group: Some(String::from("work/../tech/")),
+            ..config::Source::new(String::from("https://example.com/rss.xml"))
+        };
+        let root = Path::new("/srv/rsst");
+        assert_eq!(
+            output_dir_of(root, false, "example", &source).unwrap(),
+            root.join("example")
+        );
+        assert_eq!(
+            output_dir_of(root, true, "example", &source).unwrap(),
+            root.join("work").join("tech").join("example")
+        );
+        let source = config::Source {
+            output_dir: Some(String::from("/srv/work")),
+            ..source
+        };
+        assert_eq!(
+            output_dir_of(root, true, "example", &source).unwrap(),
+            Path::new("/srv/work/work/tech/example")
+        );
+    }
+
+    #[test]
+    fn dump_resumes_after_crash() {
+        let dir = util::scratch_dir("cli-crash");
+        let output_dir = dir.join("example");
+        let opt = Opt::from_iter(&["rsst"]);
+        let source = || Source {
+            // newest first, as in the feed.
+            article: vec![article("third"), article("second"), article("first")],
+            metadata: metadata::Metadata {
+                title: String::from("Example"),
+                checksum: String::from("third"),
+                fetched: None,
+                schedule: Schedule::default(),
+            },
+            validators: metadata::Validators::default(),
+        };
+        let mut store = Crashing {
+            store: state::open(None, &dir, false).unwrap(),
+            crash_at: "third",
+        };
+        let url = "https://example.com/rss.xml";
+        let dump_into = |store: &mut dyn StateStore| {
+            dump(&opt, store, "example", url, &output_dir, "html", source())
+        };
+        let e = dump_into(&mut store).unwrap_err();
+        assert_eq!(e.status, Status::StateError);
+        // what a crashed run left on disk.
+        let mut store = state::open(None, &dir, false).unwrap();
+        let metadata = store.metadata("example").unwrap().unwrap();
+        assert_eq!(metadata.checksum, "second");
+        let mut written: Vec<_> = fs::read_dir(&output_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        written.sort();
+        // the file of `third` was fully written, and no temporary file is left.
+        assert_eq!(written, ["first.html", "second.html", "third.html"]);
+        let second = fs::read_to_string(output_dir.join("second.html")).unwrap();
+        assert!(second.contains("second"));
+        // the next run only dumps what came after.
+        assert_eq!(dump_into(&mut *store).unwrap().unwrap(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn export_opml_locks() {
+        let dir = util::scratch_dir("cli-export");
+        let metadata_dir = dir.join("metadata");
+        let state_dir = metadata_dir.join("rsst");
+        let config_path = dir.join("config.toml");
+        let output = dir.join("subscriptions.opml");
+        fs::write(
+            &config_path,
+            format!(
+                "[setting]\nmetadata_dir = {metadata_dir:?}\n\n[source]\nexample = \"https://example.com/rss.xml\"\n",
+            ),
+        )
+        .unwrap();
+        let opt = Opt::from_iter(&["rsst"]);
+        // nothing was retrieved yet, and nothing is written in its place.
+        export_opml(&opt, &config_path, Some(&output)).unwrap();
+        assert!(fs::read_to_string(&output).unwrap().contains("example"));
+        assert!(!metadata_dir.exists());
+        create_dir_all(&state_dir).unwrap();
+        let lock = Lock::acquire(&state_dir, false).unwrap();
+        let e = export_opml(&opt, &config_path, Some(&output)).unwrap_err();
+        assert_eq!(e.status, Status::StateError);
+        drop(lock);
+        let mut store = state::open(None, &state_dir, false).unwrap();
+        let metadata = metadata::Metadata {
+            title: String::from("Example"),
+            checksum: String::new(),
+            fetched: None,
+            schedule: Schedule::default(),
+        };
+        store
+            .set_metadata("example", "https://example.com/rss.xml", metadata)
+            .unwrap();
+        store.commit().unwrap();
+        drop(store);
+        export_opml(&opt, &config_path, Some(&output)).unwrap();
+        assert!(fs::read_to_string(&output).unwrap().contains("Example"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reason_leaves_out_url() {
+        assert_eq!(failure(fetch_failed()).reason(), "the server answered 503");
+        let moved = failure(util::Error::Http {
+            url: String::from("https://example.org/rss.xml"),
+            status: 404,
+            retry_after: None,
+        });
+        assert_eq!(
+            moved.reason(),
+            "https://example.org/rss.xml: the server answered 404"
+        );
+        let invalid = failure(util::Error::FeedParseFailed {
+            url: String::from("https://example.com/rss.xml"),
+            source: "unexpected end of file".into(),
+        });
+        assert_eq!(invalid.reason(), "not a valid feed: unexpected end of file");
+        let output = failure(util::Error::NotFound {
+            path: PathBuf::from("/srv/rsst/example"),
+        });
+        assert_eq!(output.reason(), "/srv/rsst/example: no such file");
+    }
+
+    #[test]
+    fn status_of_report() {
+        let mut report = Report {
+            attempted: 2,
+            failures: vec![],
+        };
+        assert_eq!(report.status(), Status::Success);
+        report.failures.push(failure(fetch_failed()));
+        assert_eq!(report.status(), Status::PartialFailure);
+        report.failures.push(failure(fetch_failed()));
+        assert_eq!(report.status(), Status::NetworkFailure);
+        report.failures[1] = failure(util::Error::NotSupported {
+            what: String::from("output format `pdf`"),
+        });
+        assert_eq!(report.status(), Status::PartialFailure);
+    }
 }
@@ -1,12 +1,17 @@
 //! Code that manipulates the config file.
 
+use crate::opml;
+use crate::upstream;
 use crate::util;
-use serde::Deserialize;
-use std::path::PathBuf;
+use crate::validate;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 use toml;
 
 /// A Setting session that carries optional settings.
-#[derive(Deserialize)]
+#[derive(Deserialize, Default)]
 pub struct Setting {
     /// format to dump into. `"html"` or default
     pub output_format: Option<String>,
@@ -16,34 +21,646 @@ pub struct Setting {
     /// the directory to saves metadata about the feeds.
     /// Defaults to `"$XDG_DATA_HOME/rsst"`.
     pub metadata_dir: Option<String>,
+    /// where to keep the state of the feeds. `"json"` or `"sqlite"`.
+    /// Defaults to `"json"`.
+    pub state_backend: Option<String>,
+    /// how many feeds may be retrieved at the same time. Defaults to `8`.
+    pub concurrency: Option<usize>,
+    /// how many feeds from the same host may be retrieved at the same time.
+    /// Defaults to `2`.
+    pub per_host_concurrency: Option<usize>,
+    /// seconds to wait for the connection to a server. Defaults to `10`.
+    pub connect_timeout: Option<u64>,
+    /// seconds to wait for each read from a server. Defaults to `30`.
+    pub read_timeout: Option<u64>,
+    /// how many redirects to follow for a feed. Defaults to `5`.
+    pub max_redirects: Option<u32>,
+    /// whether to replace the address of a feed that has permanently moved
+    /// in this file. Defaults to `false`.
+    pub rewrite_redirects: Option<bool>,
+    /// how many times to retry a feed that failed for a reason that may go
+    /// away, such as a `503`. Defaults to `2`.
+    pub retries: Option<u32>,
+    /// seconds to wait before the first retry, doubled for each later one.
+    /// Defaults to `1`.
+    pub retry_delay: Option<u64>,
+    /// the most seconds to wait between two retries, unless the server asks
+    /// for more with `Retry-After`. Defaults to `60`.
+    pub max_retry_delay: Option<u64>,
+    /// seconds after which a run stops retrying, and fails the feeds it has
+    /// not started retrieving yet. Defaults to `600`.
+    pub max_run_time: Option<u64>,
+    /// the fewest minutes between two retrievals of a feed. Feeds may ask
+    /// for more. Defaults to `0`.
+    pub min_interval: Option<u64>,
+    /// the `User-Agent` sent to the servers. Defaults to `"rsst/<version>"`.
+    pub user_agent: Option<String>,
+    /// the proxy to go through, such as `"http://proxy.example.com:3128"` or
+    /// `"socks5://127.0.0.1:9050"`. Defaults to `$HTTPS_PROXY` or
+    /// `$HTTP_PROXY`, depending on the feed.
+    pub proxy: Option<String>,
+    /// whether the articles of a feed go under a subdirectory per group
+    /// it is nested in. Defaults to `false`.
+    pub nest_by_group: Option<bool>,
+}
+
+/// A value that should not be written in the config file, given either as
+/// the value itself, as `{ env = "VAR" }` to read it from the environment
+/// variable `VAR`, or as `{ command = "..." }` to take the first line
+/// printed by a shell command, such as `pass show feeds/example`.
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Secret {
+    Plain(String),
+    Env { env: String },
+    Command { command: String },
+}
+
+impl Secret {
+    /// Returns the value of this secret, described as `what` in errors.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the variable is not set, or if the command fails.
+    pub fn resolve(&self, what: &str) -> Result<String, util::Error> {
+        let failed = |source: util::Cause| util::Error::SecretFailed {
+            what: String::from(what),
+            source,
+        };
+        match self {
+            Secret::Plain(v) => Ok(String::clone(v)),
+            Secret::Env { env } => {
+                std::env::var(env).map_err(|e| failed(format!("${env}: {e}").into()))
+            }
+            Secret::Command { command } => {
+                let output = util::shell(command, None).map_err(|e| failed(Box::new(e)))?;
+                let output = String::from_utf8_lossy(&output);
+                Ok(String::from(output.lines().next().unwrap_or("")))
+            }
+        }
+    }
+}
+
+/// The credentials for HTTP Basic authentication.
+#[derive(Deserialize, Clone)]
+pub struct BasicAuth {
+    /// the user name.
+    pub username: String,
+    /// the password.
+    pub password: Secret,
+}
+
+/// A followed feed, given either as its address or as a table.
+#[derive(Deserialize, Default)]
+pub struct Source {
+    /// the address of the feed. A source given with `cmd` gets
+    /// `"exec:<cmd>"`.
+    #[serde(default)]
+    pub url: String,
+    /// a shell command that prints the feed, instead of `url`.
+    pub cmd: Option<String>,
+    /// a shell command that is given the feed as retrieved and prints the
+    /// feed to parse instead, e.g. to fix its encoding.
+    pub filter: Option<String>,
+    /// overrides `Setting::retries` for this feed.
+    pub retries: Option<u32>,
+    /// overrides `Setting::retry_delay` for this feed.
+    pub retry_delay: Option<u64>,
+    /// overrides `Setting::max_retry_delay` for this feed.
+    pub max_retry_delay: Option<u64>,
+    /// overrides `Setting::min_interval` for this feed.
+    pub min_interval: Option<u64>,
+    /// overrides `Setting::user_agent` for this feed.
+    pub user_agent: Option<String>,
+    /// extra HTTP headers to send for this feed, such as `Cookie`.
+    #[serde(default)]
+    pub headers: BTreeMap<String, Secret>,
+    /// the credentials for HTTP Basic authentication.
+    pub basic_auth: Option<BasicAuth>,
+    /// the token sent as `Authorization: Bearer <token>`.
+    pub bearer_token: Option<Secret>,
+    /// overrides `Setting::proxy` for this feed; `"none"` to go without.
+    pub proxy: Option<String>,
+    /// the group the feed belongs to, with nested groups joined by `/`.
+    pub group: Option<String>,
+    /// overrides `Setting::output_format` for this feed.
+    pub format: Option<String>,
+    /// overrides `Setting::output_dir` for this feed.
+    pub output_dir: Option<String>,
+    /// whether the feed is retrieved; `true` if not given.
+    pub enabled: Option<bool>,
+    /// free-form labels of the feed.
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Source {
+    /// Constructs a `Source` with only the address `url` set.
+    #[must_use]
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            ..Self::default()
+        }
+    }
+
+    /// Returns whether the feed is to be retrieved.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    /// Returns whether the feed is in `group`, either directly or through a
+    /// group nested in it, or is tagged with `group`.
+    #[must_use]
+    pub fn in_group(&self, group: &str) -> bool {
+        let group = group.trim_matches('/');
+        let nested = match &self.group {
+            Some(g) => {
+                let g = g.trim_matches('/');
+                g == group || (g.starts_with(group) && g[group.len()..].starts_with('/'))
+            }
+            None => false,
+        };
+        nested || self.tags.iter().any(|t| t == group)
+    }
+}
+
+/// Deserializes the `source` section, where each value is either an address
+/// or a table.
+fn sources<'de, D>(deserializer: D) -> Result<BTreeMap<String, Source>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Entry {
+        Url(String),
+        Table(Box<Source>),
+    }
+    let entries: BTreeMap<String, Entry> = BTreeMap::deserialize(deserializer)?;
+    let mut sources = BTreeMap::new();
+    for (alias, entry) in entries {
+        let mut source = match entry {
+            Entry::Url(url) => Source::new(url),
+            Entry::Table(source) => *source,
+        };
+        match (source.url.is_empty(), source.cmd.take()) {
+            (true, Some(cmd)) => source.url = format!("exec:{cmd}"),
+            (false, None) => (),
+            (true, None) => {
+                return Err(D::Error::custom(format!(
+                    "source `{alias}` needs either `url` or `cmd`"
+                )))
+            }
+            (false, Some(_)) => {
+                return Err(D::Error::custom(format!(
+                    "source `{alias}` can't have both `url` and `cmd`"
+                )))
+            }
+        }
+        sources.insert(alias, source);
+    }
+    Ok(sources)
 }
 
 /// A top level Configuration.
 #[derive(Deserialize)]
 pub struct Config {
     /// setting section (fields optional)
+    #[serde(default)]
     pub setting: Setting,
-    /// source section (fields are `alias -> source url` mappings)
-    pub source: std::collections::BTreeMap<String, String>,
+    /// source section (fields are `alias -> source` mappings)
+    #[serde(default, deserialize_with = "sources")]
+    pub source: BTreeMap<String, Source>,
+}
+
+/// Returns the path of the config file, which is `filepath` if given.
+///
+/// # Errors
+///
+/// Fails if no config file is given and none can be found.
+///
+/// # Panics
+///
+/// Panics if `filepath` is not valid UTF-8.
+pub fn path(filepath: Option<PathBuf>) -> Result<PathBuf, util::Error> {
+    util::get_config_file(
+        filepath.map(|v| String::from(v.to_str().expect("filepath is not legal"))),
+    )
+}
+
+/// Converts the error `e` met when parsing `text`, the content of the config
+/// file at `path`.
+fn parse_error(path: PathBuf, text: &str, e: toml::de::Error) -> util::Error {
+    let mut position = e.line_col().map(|(line, column)| (line + 1, column + 1));
+    let mut message = e.to_string();
+    if let Some((line, _)) = position {
+        // the position is reported on its own.
+        let suffix = format!(" at line {line}");
+        if message.ends_with(&suffix) {
+            message.truncate(message.len() - suffix.len());
+        }
+    } else {
+        let (found, clearer) = validate::position(text, &message);
+        position = found;
+        message = clearer.unwrap_or(message);
+    }
+    util::Error::ParseFailed {
+        path,
+        line: position.map(|(line, _)| line),
+        column: position.map(|(_, column)| column),
+        message,
+        source: Some(Box::new(e)),
+    }
 }
 
-/// Try deserializing the file at the given `filepath` into a `String`.
-fn to_string(filepath: Option<PathBuf>) -> Result<String, util::Error> {
-    let filepath = util::get_config_file(match filepath {
-        Some(v) => Some(String::from(v.to_str().expect("filepath is not legal"))),
-        None => None,
-    })?;
-    util::to_string(filepath)
+/// Parses `text`, the content of the config file at `path`, into a
+/// `toml::Value`, checking it could be deserialized into a `Config` on its
+/// own. Returns every problem found otherwise.
+fn parse_value(path: &Path, text: &str) -> Result<toml::Value, Vec<util::Error>> {
+    let value: toml::Value =
+        toml::from_str(text).map_err(|e| vec![parse_error(PathBuf::from(path), text, e)])?;
+    let mut problems = validate::check(path, text, &value);
+    if problems.is_empty() {
+        match toml::from_str::<Config>(text) {
+            Ok(_) => return Ok(value),
+            Err(e) => problems.push(parse_error(PathBuf::from(path), text, e)),
+        }
+    }
+    Err(problems)
+}
+
+/// Returns the files the config file at `path` includes, whose `include`
+/// key is `include`: those matching each of the patterns, then those in the
+/// `config.d` directory next to it, each in alphabetical order. Patterns
+/// are relative to the directory of the config file. Problems met are
+/// reported into `problems`.
+fn included(
+    path: &Path,
+    text: &str,
+    include: Option<&toml::Value>,
+    problems: &mut Vec<util::Error>,
+) -> Vec<PathBuf> {
+    let dir = glob::Pattern::escape(
+        &path
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .to_string_lossy(),
+    );
+    let relative = |pattern: &str| match dir.as_str() {
+        "" => String::from(pattern),
+        dir => format!("{dir}/{pattern}"),
+    };
+    let mut patterns = vec![];
+    let listed = include
+        .and_then(toml::Value::as_array)
+        .map_or(&[][..], Vec::as_slice);
+    for pattern in listed.iter().filter_map(toml::Value::as_str) {
+        match util::expand(pattern) {
+            Ok(expanded) if expanded.is_absolute() => {
+                patterns.push((Some(pattern), expanded.to_string_lossy().into_owned()));
+            }
+            Ok(expanded) => patterns.push((Some(pattern), relative(&expanded.to_string_lossy()))),
+            Err(e) => problems.push(validate::problem_at(
+                path,
+                text,
+                &["include"],
+                e.to_string(),
+            )),
+        }
+    }
+    patterns.push((None, relative("config.d/*.toml")));
+    let mut files = vec![];
+    for (pattern, expanded) in patterns {
+        let matches: Vec<PathBuf> = match glob::glob(&expanded) {
+            Ok(matches) => matches
+                .filter_map(Result::ok)
+                .filter(|p| p.is_file())
+                .collect(),
+            Err(e) => {
+                let message = format!("invalid pattern `{}`: {}", pattern.unwrap_or(""), e);
+                problems.push(validate::problem_at(path, text, &["include"], message));
+                continue;
+            }
+        };
+        if let Some(pattern) = pattern {
+            if matches.is_empty() && glob::Pattern::escape(pattern) == pattern {
+                let message = format!("included file `{pattern}` does not exist");
+                problems.push(validate::problem_at(path, text, &["include"], message));
+            }
+        }
+        for file in matches {
+            if file != path && !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+/// Returns `path`, followed by the position of `key` in `text`, its
+/// content, if found.
+fn location(path: &Path, text: &str, key: &[&str]) -> String {
+    match validate::locate(text, key).first() {
+        Some((line, column)) => format!("{}:{}:{}", path.display(), line, column),
+        None => path.display().to_string(),
+    }
+}
+
+/// Merges the `setting` and `source` sections of `files`, given as their
+/// paths, contents and parsed values, reporting the keys defined in more
+/// than one of them into `problems`.
+fn merge(
+    files: &[(PathBuf, String, toml::Value)],
+    problems: &mut Vec<util::Error>,
+) -> toml::value::Table {
+    // where each key of `setting` and `source` was first defined.
+    let mut origins: BTreeMap<(&str, &str), String> = BTreeMap::new();
+    let mut merged = toml::value::Table::new();
+    for (file, text, value) in files {
+        for section in &["setting", "source"] {
+            let Some(toml::Value::Table(entries)) = value.get(*section) else {
+                continue;
+            };
+            let into = merged
+                .entry(String::from(*section))
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+            for (key, value) in entries {
+                let at = [*section, key.as_str()];
+                if let Some(origin) = origins.get(&(*section, key.as_str())) {
+                    let message = format!("{section} `{key}` is already defined at {origin}");
+                    problems.push(validate::problem_at(file, text, &at, message));
+                    continue;
+                }
+                origins.insert((*section, key.as_str()), location(file, text, &at));
+                if let toml::Value::Table(into) = into {
+                    into.insert(String::clone(key), value.clone());
+                }
+            }
+        }
+    }
+    merged
+}
+
+/// Parses `text`, the content of the config file at `path`, into a
+/// `Config`, along with the files it includes. Every problem found is
+/// reported, with its position if known.
+///
+/// # Errors
+///
+/// Fails with the problem found, or `util::Error::Invalid` listing all of
+/// them if there are several.
+pub fn parse(path: &Path, text: &str) -> Result<Config, util::Error> {
+    let mut problems = vec![];
+    let mut files = vec![];
+    match parse_value(path, text) {
+        Ok(value) => {
+            let paths = included(path, text, value.get("include"), &mut problems);
+            files.push((PathBuf::from(path), String::from(text), value));
+            for file in paths {
+                let text = match util::to_string(&file) {
+                    Ok(text) => text,
+                    Err(e) => {
+                        problems.push(e);
+                        continue;
+                    }
+                };
+                match parse_value(&file, &text) {
+                    Ok(value) => files.push((file, text, value)),
+                    Err(mut found) => problems.append(&mut found),
+                }
+            }
+        }
+        Err(mut found) => problems.append(&mut found),
+    }
+    for (file, text, value) in files.iter().skip(1) {
+        if value.get("include").is_some() {
+            let message = String::from("`include` is only allowed in the main config file");
+            problems.push(validate::problem_at(file, text, &["include"], message));
+        }
+    }
+    let merged = merge(&files, &mut problems);
+    match problems.len() {
+        0 => toml::Value::Table(merged)
+            .try_into()
+            .map_err(|e| parse_error(PathBuf::from(path), text, e)),
+        1 => Err(problems.remove(0)),
+        _ => Err(util::Error::Invalid { problems }),
+    }
 }
 
 /// Try deserializing the given file into a `Config`. Use the default filepath
 /// if not given.
 pub fn get(name: Option<PathBuf>) -> Result<Config, util::Error> {
-    let output = to_string(name)?;
-    match toml::from_str(&output) {
-        Ok(v) => Ok(v),
-        Err(_) => Err(util::Error::ParseFailed),
+    let path = path(name)?;
+    let output = util::to_string(&path)?;
+    parse(&path, &output)
+}
+
+/// Replaces every `"from"` or `'from'` string in the config file at `path`
+/// with `"to"`, leaving the rest of the file as it was. Returns whether
+/// anything was replaced.
+///
+/// # Errors
+///
+/// Fails if the config file can't be read or written.
+pub fn rewrite_url(path: &Path, from: &str, to: &str) -> Result<bool, util::Error> {
+    let text = util::to_string(path)?;
+    let quoted = toml::Value::String(String::from(to)).to_string();
+    let rewritten = text
+        .replace(&format!("\"{from}\""), &quoted)
+        .replace(&format!("'{from}'"), &quoted);
+    if rewritten == text {
+        return Ok(false);
+    }
+    util::write_atomic(path, rewritten.as_bytes())?;
+    Ok(true)
+}
+
+/// Turns `title` into an alias: lowercase ASCII letters and digits, with
+/// anything else between them replaced by `-`.
+fn slug(title: &str) -> String {
+    let mut alias = String::new();
+    for c in title.chars() {
+        if c.is_ascii_alphanumeric() {
+            alias.push(c.to_ascii_lowercase());
+        } else if !alias.is_empty() && !alias.ends_with('-') {
+            alias.push('-');
+        }
+    }
+    let len = alias.trim_end_matches('-').len();
+    alias.truncate(len);
+    alias
+}
+
+/// Returns an alias for a feed titled `title` at `url` that is not in
+/// `taken`, falling back on the host of `url` when the title has nothing
+/// usable, and appending `-2`, `-3`... until it is unique.
+fn unique_alias(title: &str, url: &str, taken: &BTreeSet<String>) -> String {
+    let mut base = slug(title);
+    if base.is_empty() {
+        base = url::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(slug))
+            .unwrap_or_default();
+    }
+    if base.is_empty() {
+        base = String::from("feed");
+    }
+    let mut alias = String::clone(&base);
+    let mut n = 1;
+    while taken.contains(&alias) {
+        n += 1;
+        alias = format!("{base}-{n}");
+    }
+    alias
+}
+
+/// Formats the `source` entry for the feed `url` in `group`.
+fn entry(alias: &str, url: &str, group: Option<&str>) -> String {
+    let quote = |s: &str| toml::Value::String(String::from(s)).to_string();
+    match group {
+        Some(group) => format!(
+            "{} = {{ url = {}, group = {} }}\n",
+            alias,
+            quote(url),
+            quote(group)
+        ),
+        None => format!("{} = {}\n", alias, quote(url)),
+    }
+}
+
+/// Returns the table header on `line`, without its comment, if there is one.
+fn header(line: &str) -> Option<&str> {
+    let line = line.split('#').next().unwrap_or_default().trim();
+    Some(line).filter(|l| l.starts_with('['))
+}
+
+/// Returns `lines` joined back, with `entries` inserted after the line at
+/// `index`, or before the first one if there is none.
+fn splice(lines: &[&str], index: Option<usize>, entries: &str) -> String {
+    let at = index.map_or(0, |i| i + 1);
+    let mut merged = String::new();
+    for line in &lines[..at] {
+        merged.push_str(line);
+        merged.push('\n');
+    }
+    merged.push_str(entries);
+    for line in &lines[at..] {
+        merged.push_str(line);
+        merged.push('\n');
     }
+    merged
+}
+
+/// Inserts `entries` at the end of the `[source]` table of `text`. Without
+/// that table, they go after the last dotted `source.<alias>` key, or in a new
+/// `[source]` table before the first `[source.<alias>]` one or at the end of
+/// `text`.
+fn insert_sources(text: &str, entries: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let Some(start) = lines.iter().position(|l| header(l) == Some("[source]")) else {
+        // dotted keys can only be given before the first header.
+        let root = lines
+            .iter()
+            .position(|l| header(l).is_some())
+            .unwrap_or(lines.len());
+        if let Some(last) = lines[..root]
+            .iter()
+            .rposition(|l| l.trim_start().starts_with("source."))
+        {
+            let dotted: String = entries.lines().flat_map(|e| ["source.", e, "\n"]).collect();
+            return splice(&lines, Some(last), &dotted);
+        }
+        if let Some(first) = lines
+            .iter()
+            .position(|l| header(l).is_some_and(|h| h.starts_with("[source.")))
+        {
+            return splice(
+                &lines,
+                first.checked_sub(1),
+                &format!("[source]\n{entries}\n"),
+            );
+        }
+        let mut text = String::from(text);
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        return text + "[source]\n" + entries;
+    };
+    // the table ends before the next header, or with the file.
+    let end = lines[start + 1..]
+        .iter()
+        .position(|l| header(l).is_some())
+        .map_or(lines.len(), |i| start + 1 + i);
+    // the entries go right after the last line of the table that is not blank.
+    let last = lines[start + 1..end]
+        .iter()
+        .rposition(|l| !l.trim().is_empty())
+        .map_or(start, |i| start + 1 + i);
+    splice(&lines, Some(last), entries)
+}
+
+/// What importing feeds into the config file has done.
+#[derive(Debug, Default)]
+pub struct Imported {
+    /// the aliases given to the added feeds, along with their addresses.
+    pub added: Vec<(String, String)>,
+    /// the addresses of the feeds that were already followed.
+    pub skipped: Vec<String>,
+    /// the addresses of the feeds that are not served over HTTP or HTTPS,
+    /// such as commands or local files, which are never imported.
+    pub rejected: Vec<String>,
+}
+
+/// Adds the `feeds` to the `source` section of the config file at `path`,
+/// creating it if it does not exist, and leaving the rest of the file as it
+/// was. Feeds whose address is already followed are skipped.
+///
+/// # Errors
+///
+/// Fails if the config file can't be read or written, or would no longer be
+/// valid with the feeds added.
+pub fn import(path: &Path, feeds: &[opml::Feed]) -> Result<Imported, util::Error> {
+    let text = match util::to_string(path) {
+        Ok(text) => text,
+        Err(util::Error::NotFound { .. }) => String::from("[setting]\n\n[source]\n"),
+        Err(e) => return Err(e),
+    };
+    let config = parse(path, &text)?;
+    let mut taken: BTreeSet<String> = config.source.keys().cloned().collect();
+    let mut followed: BTreeSet<&str> = config.source.values().map(|s| s.url.as_str()).collect();
+    let mut imported = Imported::default();
+    let mut entries = String::new();
+    for feed in feeds {
+        // a shared list must not turn into commands or local files to read.
+        if !upstream::is_http(&feed.url) {
+            imported.rejected.push(String::clone(&feed.url));
+            continue;
+        }
+        if !followed.insert(feed.url.as_str()) {
+            imported.skipped.push(String::clone(&feed.url));
+            continue;
+        }
+        let alias = unique_alias(&feed.title, &feed.url, &taken);
+        entries.push_str(&entry(&alias, &feed.url, feed.group.as_deref()));
+        taken.insert(String::clone(&alias));
+        imported.added.push((alias, String::clone(&feed.url)));
+    }
+    if imported.added.is_empty() {
+        return Ok(imported);
+    }
+    let merged = insert_sources(&text, &entries);
+    parse(path, &merged)?;
+    if let Some(parent) = path.parent() {
+        util::create_dir_all(parent)?;
+    }
+    util::write_atomic(path, merged.as_bytes())?;
+    Ok(imported)
 }
 
 #[cfg(test)]
@@ -73,12 +690,12 @@ mod tests {
         );
         assert_eq!(config.source.len(), 2);
         assert_eq!(
-            config.source.get("example1"),
-            Some(&"https://example.com/rss.xml".to_string())
+            config.source.get("example1").map(|s| s.url.as_str()),
+            Some("https://example.com/rss.xml")
         );
         assert_eq!(
-            config.source.get("example2"),
-            Some(&"https://example.org/rss.xml".to_string())
+            config.source.get("example2").map(|s| s.url.as_str()),
+            Some("https://example.org/rss.xml")
         );
     }
 
@@ -95,21 +712,149 @@ mod tests {
         assert_eq!(config.setting.output_format, Some(String::from("markdown")));
         assert_eq!(config.source.len(), 2);
         assert_eq!(
-            config.source.get("example3"),
-            Some(&"https://example.com/rss.xml".to_string())
+            config.source.get("example3").map(|s| s.url.as_str()),
+            Some("https://example.com/rss.xml")
         );
         assert_eq!(
-            config.source.get("example4"),
-            Some(&"https://example.org/rss.xml".to_string())
+            config.source.get("example4").map(|s| s.url.as_str()),
+            Some("https://example.org/rss.xml")
         );
     }
 
     #[test]
-    fn to_string_none() {
+    fn parse_source_table() {
+        let config: Config = toml::from_str(
+            r#"
+            [setting]
+            retries = 5
+            [source]
+            plain = "https://example.com/rss.xml"
+            flaky = { url = "https://example.org/rss.xml", retries = 10, retry_delay = 30 }
+            weekly = { url = "https://example.net/rss.xml", min_interval = 10080 }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(config.setting.retries, Some(5));
+        let plain = &config.source["plain"];
+        assert_eq!(plain.url, "https://example.com/rss.xml");
+        assert_eq!(plain.retries, None);
+        let flaky = &config.source["flaky"];
+        assert_eq!(flaky.url, "https://example.org/rss.xml");
+        assert_eq!(flaky.retries, Some(10));
+        assert_eq!(flaky.retry_delay, Some(30));
+        assert_eq!(flaky.max_retry_delay, None);
+        assert_eq!(config.source["weekly"].min_interval, Some(10080));
+    }
+
+    #[test]
+    fn parse_source_overrides() {
+        let config: Config = toml::from_str(
+            r#"
+            [setting]
+            output_format = "html"
+            [source]
+            plain = "https://example.com/rss.xml"
+            [source.example]
+            url = "https://example.org/rss.xml"
+            format = "markdown"
+            output_dir = "/srv/feeds"
+            enabled = false
+            tags = ["work", "daily"]
+        "#,
+        )
+        .unwrap();
+        let plain = &config.source["plain"];
+        assert!(plain.is_enabled());
+        assert_eq!(plain.format, None);
+        assert!(plain.tags.is_empty());
+        let example = &config.source["example"];
+        assert_eq!(example.url, "https://example.org/rss.xml");
+        assert_eq!(example.format, Some(String::from("markdown")));
+        assert_eq!(example.output_dir, Some(String::from("/srv/feeds")));
+        assert!(!example.is_enabled());
+        assert_eq!(example.tags, vec!["work", "daily"]);
+    }
+
+    #[test]
+    fn source_groups() {
+        let source = Source {
+            group: Some(String::from("work/tech")),
+            tags: vec![String::from("daily")],
+            ..Source::new(String::from("https://example.com/rss.xml"))
+        };
+        assert!(source.in_group("work"));
+        assert!(source.in_group("work/tech"));
+        assert!(source.in_group("work/"));
+        assert!(source.in_group("daily"));
+        assert!(!source.in_group("wor"));
+        assert!(!source.in_group("tech"));
+        assert!(!Source::new(String::from("https://example.com/rss.xml")).in_group("work"));
+    }
+
+    #[test]
+    fn parse_source_command() {
+        let config: Config = toml::from_str(
+            r#"
+            [setting]
+            [source]
+            scraped = { cmd = "my-scraper --site foo", filter = "iconv -f latin1" }
+            short = "exec:my-scraper --site bar"
+        "#,
+        )
+        .unwrap();
+        assert_eq!(config.source["scraped"].url, "exec:my-scraper --site foo");
+        assert_eq!(config.source["scraped"].cmd, None);
         assert_eq!(
-            to_string(Some(PathBuf::from("NOT_EXISTS"))),
-            Err(util::Error::NotFound)
+            config.source["scraped"].filter,
+            Some(String::from("iconv -f latin1"))
         );
+        assert_eq!(config.source["short"].url, "exec:my-scraper --site bar");
+        for invalid in &[
+            "[setting]\n[source]\nboth = { url = \"https://example.com\", cmd = \"true\" }",
+            "[setting]\n[source]\nneither = { retries = 1 }",
+        ] {
+            assert!(toml::from_str::<Config>(invalid).is_err());
+        }
+    }
+
+    #[test]
+    fn parse_source_credentials() {
+        let config: Config = toml::from_str(
+            r#"
+            [setting]
+            [source.internal]
+            url = "https://intranet.example.com/rss.xml"
+            user_agent = "Mozilla/5.0"
+            headers = { Cookie = { env = "RSST_TEST_COOKIE" } }
+            basic_auth = { username = "me", password = { command = "echo secret; echo ignored" } }
+            bearer_token = "plain"
+        "#,
+        )
+        .unwrap();
+        let source = &config.source["internal"];
+        assert_eq!(source.user_agent, Some(String::from("Mozilla/5.0")));
+        env::set_var("RSST_TEST_COOKIE", "session=42");
+        assert_eq!(
+            source.headers["Cookie"].resolve("cookie").unwrap(),
+            "session=42"
+        );
+        let auth = source.basic_auth.as_ref().unwrap();
+        assert_eq!(auth.username, "me");
+        assert_eq!(auth.password.resolve("password").unwrap(), "secret");
+        let token = source.bearer_token.as_ref().unwrap();
+        assert_eq!(token.resolve("token").unwrap(), "plain");
+        let undefined = Secret::Env {
+            env: String::from("RSST_TEST_UNDEFINED"),
+        };
+        assert!(undefined.resolve("token").is_err());
+    }
+
+    #[test]
+    fn to_string_none() {
+        assert!(matches!(
+            path(Some(PathBuf::from("NOT_EXISTS"))).and_then(|path| util::to_string(&path)),
+            Err(util::Error::NotFound { .. })
+        ));
     }
 
     #[test]
@@ -118,7 +863,10 @@ mod tests {
             .expect("failed to get current dir")
             .join("fixtures/empty/");
         env::set_var("XDG_CONFIG_HOME", fixtures);
-        assert_eq!(to_string(None), Ok(String::from("")));
+        assert_eq!(
+            path(None).and_then(|path| util::to_string(&path)).unwrap(),
+            ""
+        );
     }
 
     #[test]
@@ -127,7 +875,10 @@ mod tests {
             .expect("failed to get current dir")
             .join("fixtures/NON_EXISTS/");
         env::set_var("XDG_CONFIG_HOME", fixtures);
-        assert_eq!(to_string(None), Err(util::Error::NotFound));
+        assert!(matches!(
+            path(None).and_then(|path| util::to_string(&path)),
+            Err(util::Error::NotFound { .. })
+        ));
     }
 
     #[test]
@@ -136,7 +887,10 @@ mod tests {
             .expect("failed to get current dir")
             .join("fixtures/nothing/");
         env::set_var("XDG_CONFIG_HOME", fixtures);
-        assert_eq!(to_string(None), Err(util::Error::NotFound));
+        assert!(matches!(
+            path(None).and_then(|path| util::to_string(&path)),
+            Err(util::Error::NotFound { .. })
+        ));
     }
 
     #[test]
@@ -149,13 +903,293 @@ mod tests {
         assert_eq!(config.setting.output_format, Some(String::from("html")));
         assert_eq!(config.source.len(), 2);
         assert_eq!(
-            config.source.get("mine"),
-            Some(&"https://quinoa42.github.io/rss.xml".to_string())
+            config.source.get("mine").map(|s| s.url.as_str()),
+            Some("https://quinoa42.github.io/rss.xml")
+        );
+        assert_eq!(
+            config.source.get("again").map(|s| s.url.as_str()),
+            Some("quinoa42.github.io/rss.xml")
+        );
+    }
+
+    #[test]
+    fn parse_error_reports_position() {
+        let text = "[setting]\noutput_format = \n";
+        let e = parse_error(
+            PathBuf::from("config.toml"),
+            text,
+            toml::from_str::<Config>(text)
+                .err()
+                .expect("should not parse"),
+        );
+        match e {
+            util::Error::ParseFailed { line, .. } => assert_eq!(line, Some(2)),
+            _ => panic!("unexpected error {:?}", e),
+        }
+        assert!(e.to_string().starts_with("config.toml:2:"));
+    }
+
+    #[test]
+    fn parse_reports_problems() {
+        let path = Path::new("config.toml");
+        let e = parse(path, "[setting]\nretries = \"x\"\n").err().unwrap();
+        assert!(e.to_string().starts_with("config.toml:2:1: invalid type"));
+        let e = parse(
+            path,
+            "[source]\na = \"https://a.org\"\na = \"https://b.org\"\n",
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            e.to_string(),
+            "config.toml:3:1: source `a` is defined again; first defined at line 2"
+        );
+        let e = parse(
+            path,
+            "output_dir = \"/srv\"\n[source]\na = \"https://exa mple.org\"\n",
+        )
+        .err()
+        .unwrap();
+        match e {
+            util::Error::Invalid { problems } => {
+                assert_eq!(problems.len(), 2);
+                assert_eq!(
+                    problems[0].to_string(),
+                    "config.toml:1:1: unknown key `output_dir`; did you mean to put it in [setting]?"
+                );
+                assert!(problems[1]
+                    .to_string()
+                    .starts_with("config.toml:3:1: source `a`: invalid address"));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn get_included() {
+        let filepath = env::current_dir()
+            .expect("failed to get current dir")
+            .join("fixtures/include/config.toml");
+        let config = get(Some(filepath)).unwrap();
+        assert_eq!(config.setting.output_format, Some(String::from("html")));
+        assert_eq!(config.setting.retries, Some(5));
+        let aliases: Vec<_> = config.source.keys().map(String::as_str).collect();
+        assert_eq!(aliases, vec!["blog", "mine", "standup"]);
+        assert_eq!(config.source["standup"].group, Some(String::from("work")));
+    }
+
+    #[test]
+    fn included_conflicts() {
+        let dir = util::scratch_dir("config-include");
+        std::fs::create_dir_all(dir.join("config.d")).unwrap();
+        let main = dir.join("config.toml");
+        std::fs::write(
+            &main,
+            "include = [\"shared.toml\", \"missing.toml\"]\n[source]\nfoo = \"https://a.org/rss\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("shared.toml"),
+            "[source]\nbar = \"https://b.org/rss\"\n\n[source.foo]\nurl = \"https://c.org/rss\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("config.d/mine.toml"),
+            "[setting]\nretries = 1\n[source]\nbar = \"https://d.org/rss\"\n",
+        )
+        .unwrap();
+        let problems: Vec<_> = match get(Some(PathBuf::clone(&main))).err().unwrap() {
+            util::Error::Invalid { problems } => problems.iter().map(ToString::to_string).collect(),
+            e => panic!("unexpected error {:?}", e),
+        };
+        let at = |name: &str| dir.join(name).display().to_string();
+        assert_eq!(
+            problems,
+            vec![
+                format!(
+                    "{}:1:1: included file `missing.toml` does not exist",
+                    at("config.toml")
+                ),
+                format!(
+                    "{}:4:1: source `foo` is already defined at {}:3:1",
+                    at("shared.toml"),
+                    at("config.toml")
+                ),
+                format!(
+                    "{}:4:1: source `bar` is already defined at {}:2:1",
+                    at("config.d/mine.toml"),
+                    at("shared.toml")
+                ),
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn get_empty_config() {
+        let filepath = env::current_dir()
+            .expect("failed to get current dir")
+            .join("fixtures/empty/rsst/config.toml");
+        let config = get(Some(filepath)).unwrap();
+        assert_eq!(config.setting.output_format, None);
+        assert!(config.source.is_empty());
+    }
+
+    #[test]
+    fn rewrite_url_keeps_the_rest() {
+        let dir = util::scratch_dir("config-rewrite");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "# my feeds\n[source]\nmine = \"http://a.org/rss\" # old\nother = \"http://b.org/rss\"\n",
+        )
+        .unwrap();
+        assert!(rewrite_url(&path, "http://a.org/rss", "https://a.org/rss").unwrap());
+        assert_eq!(
+            util::to_string(&path).unwrap(),
+            "# my feeds\n[source]\nmine = \"https://a.org/rss\" # old\nother = \"http://b.org/rss\"\n"
+        );
+        assert!(!rewrite_url(&path, "http://c.org/rss", "https://c.org/rss").unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unique_aliases() {
+        let taken: BTreeSet<String> = vec![String::from("tom-jerry"), String::from("tom-jerry-2")]
+            .into_iter()
+            .collect();
+        assert_eq!(slug("  Tom & Jerry's Blog!"), "tom-jerry-s-blog");
+        assert_eq!(
+            unique_alias("Tom & Jerry", "https://example.com/", &taken),
+            "tom-jerry-3"
+        );
+        assert_eq!(
+            unique_alias("日記", "https://blog.example.com/rss", &taken),
+            "blog-example-com"
+        );
+        assert_eq!(unique_alias("", "exec:scrape", &taken), "feed");
+    }
+
+    #[test]
+    fn import_keeps_the_rest() {
+        let dir = util::scratch_dir("config-import");
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            "[setting]\n\n[source]\nexample = \"https://example.com/rss.xml\" # mine\n\n[source.other]\nurl = \"https://example.net/rss\"\n",
+        )
+        .unwrap();
+        let feeds = vec![
+            opml::Feed {
+                title: String::from("Example"),
+                url: String::from("https://example.org/feed"),
+                group: Some(String::from("Work/Tech")),
+            },
+            opml::Feed {
+                title: String::from("Example"),
+                url: String::from("https://example.com/rss.xml"),
+                group: None,
+            },
+            opml::Feed {
+                title: String::from("Other"),
+                url: String::from("https://other.example.com/rss"),
+                group: None,
+            },
+        ];
+        let imported = import(&path, &feeds).unwrap();
+        assert_eq!(
+            imported.added,
+            vec![
+                (
+                    String::from("example-2"),
+                    String::from("https://example.org/feed")
+                ),
+                (
+                    String::from("other-2"),
+                    String::from("https://other.example.com/rss")
+                ),
+            ]
+        );
+        assert_eq!(
+            imported.skipped,
+            vec![String::from("https://example.com/rss.xml")]
+        );
+        assert_eq!(
+            util::to_string(&path).unwrap(),
+            "[setting]\n\n[source]\nexample = \"https://example.com/rss.xml\" # mine\n\
+             example-2 = { url = \"https://example.org/feed\", group = \"Work/Tech\" }\n\
+             other-2 = \"https://other.example.com/rss\"\n\n\
+             [source.other]\nurl = \"https://example.net/rss\"\n"
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(import(&path, &feeds[..1]).unwrap().added.len(), 1);
+        assert_eq!(
+            util::to_string(&path).unwrap(),
+            "[setting]\n\n[source]\nexample = { url = \"https://example.org/feed\", group = \"Work/Tech\" }\n"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn insert_sources_anywhere() {
+        let entry = "new = \"https://example.org/feed\"\n";
+        let commented =
+            "[source] # feeds\nexample = \"https://example.com/rss.xml\"\n\n[setting]\n";
+        assert_eq!(
+            insert_sources(commented, entry),
+            "[source] # feeds\nexample = \"https://example.com/rss.xml\"\nnew = \"https://example.org/feed\"\n\n[setting]\n"
+        );
+        let dotted = "source.example = \"https://example.com/rss.xml\"\n\n[setting]\n";
+        assert_eq!(
+            insert_sources(dotted, entry),
+            "source.example = \"https://example.com/rss.xml\"\nsource.new = \"https://example.org/feed\"\n\n[setting]\n"
+        );
+        let tables = "[setting]\n\n[source.example]\nurl = \"https://example.com/rss.xml\"\n";
+        assert_eq!(
+            insert_sources(tables, entry),
+            "[setting]\n\n[source]\nnew = \"https://example.org/feed\"\n\n[source.example]\nurl = \"https://example.com/rss.xml\"\n"
+        );
+        for text in &[commented, dotted, tables] {
+            let path = Path::new("config.toml");
+            let config = parse(path, &insert_sources(text, entry)).unwrap();
+            assert_eq!(config.source.len(), 2);
+        }
+    }
+
+    #[test]
+    fn import_rejects_local_sources() {
+        let dir = util::scratch_dir("config-import-local");
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "[source]\n").unwrap();
+        let feed = |url: &str| opml::Feed {
+            title: String::from("Nice feed"),
+            url: String::from(url),
+            group: None,
+        };
+        let feeds = vec![
+            feed("exec:touch /tmp/rsst-pwned"),
+            feed("file:///etc/passwd"),
+            feed("/etc/passwd"),
+            feed("-"),
+            feed("ftp://example.com/rss.xml"),
+            feed("example.com/rss.xml"),
+        ];
+        let imported = import(&path, &feeds).unwrap();
+        assert_eq!(
+            imported.rejected,
+            vec![
+                "exec:touch /tmp/rsst-pwned",
+                "file:///etc/passwd",
+                "/etc/passwd",
+                "-",
+                "ftp://example.com/rss.xml",
+            ]
         );
         assert_eq!(
-            config.source.get("again"),
-            Some(&"quinoa42.github.io/rss.xml".to_string())
+            util::to_string(&path).unwrap(),
+            "[source]\nnice-feed = \"example.com/rss.xml\"\n"
         );
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
@@ -167,12 +1201,12 @@ mod tests {
         assert_eq!(config.setting.output_format, Some(String::from("html")));
         assert_eq!(config.source.len(), 2);
         assert_eq!(
-            config.source.get("mine"),
-            Some(&"https://quinoa42.github.io/rss.xml".to_string())
+            config.source.get("mine").map(|s| s.url.as_str()),
+            Some("https://quinoa42.github.io/rss.xml")
         );
         assert_eq!(
-            config.source.get("again"),
-            Some(&"quinoa42.github.io/rss.xml".to_string())
+            config.source.get("again").map(|s| s.url.as_str()),
+            Some("quinoa42.github.io/rss.xml")
         );
     }
 }
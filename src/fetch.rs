@@ -0,0 +1,312 @@
+//! Code that retrieves many feeds at once.
+
+use crate::http::{Client, Options};
+use crate::metadata::Validators;
+use crate::retry::{self, Policy};
+use crate::upstream::{to_source, Retrieved};
+use crate::util;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// A feed to retrieve.
+#[derive(Debug, Clone)]
+pub struct Job {
+    /// the alias of the feed.
+    pub alias: String,
+    /// the address of the feed.
+    pub url: String,
+    /// the HTTP cache validators recorded for the feed, if any.
+    pub validators: Option<Validators>,
+    /// how to retry the feed if it could not be retrieved.
+    pub retry: Policy,
+    /// how to make the requests for the feed.
+    pub options: Options,
+    /// the shell command to pass the feed through before parsing it.
+    pub filter: Option<String>,
+}
+
+/// How many feeds may be retrieved at the same time.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// the limit across all hosts.
+    pub total: usize,
+    /// the limit for any single host.
+    pub per_host: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            total: 8,
+            per_host: 2,
+        }
+    }
+}
+
+/// Returns the host part of `url`, used to apply `Limits::per_host`.
+fn host(url: &str) -> String {
+    let rest = match url.find("://") {
+        Some(i) => &url[i + 3..],
+        None => url,
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or("");
+    let host = match authority.rfind('@') {
+        Some(i) => &authority[i + 1..],
+        None => authority,
+    };
+    host.to_lowercase()
+}
+
+/// The jobs not started yet and the number of running jobs of each host.
+struct Queue {
+    /// indices of the jobs not started yet, in order.
+    pending: Vec<usize>,
+    /// number of running jobs of each host.
+    running: HashMap<String, usize>,
+}
+
+/// A job taken from the `Queue`.
+enum Taken {
+    /// the index of a job to retrieve.
+    Run(usize),
+    /// the index of a job left when the deadline passed, to fail unretrieved.
+    Expired(usize),
+}
+
+/// Shares a `Queue` between the workers.
+type Shared = Arc<(Mutex<Queue>, Condvar)>;
+
+/// Takes the first pending job whose host is below `per_host`, waiting for
+/// one if there is none. Once `deadline` has passed, the pending jobs are
+/// taken in order as expired instead. Returns `None` once every job has been
+/// taken.
+fn take(
+    shared: &Shared,
+    jobs: &[Job],
+    per_host: usize,
+    deadline: Option<Instant>,
+) -> Option<Taken> {
+    let (lock, cvar) = &**shared;
+    let mut queue = lock.lock().expect("a fetching thread panicked");
+    loop {
+        if queue.pending.is_empty() {
+            return None;
+        }
+        let now = Instant::now();
+        if deadline.is_some_and(|deadline| now >= deadline) {
+            return Some(Taken::Expired(queue.pending.remove(0)));
+        }
+        let free = queue.pending.iter().position(|&i| {
+            queue
+                .running
+                .get(&host(&jobs[i].url))
+                .is_none_or(|&n| n < per_host)
+        });
+        if let Some(position) = free {
+            let i = queue.pending.remove(position);
+            *queue.running.entry(host(&jobs[i].url)).or_insert(0) += 1;
+            return Some(Taken::Run(i));
+        }
+        queue = match deadline {
+            Some(deadline) => {
+                cvar.wait_timeout(queue, deadline - now)
+                    .expect("a fetching thread panicked")
+                    .0
+            }
+            None => cvar.wait(queue).expect("a fetching thread panicked"),
+        };
+    }
+}
+
+/// Marks a job of `host` as finished.
+fn finish(shared: &Shared, host: &str) {
+    let (lock, cvar) = &**shared;
+    let mut queue = lock.lock().expect("a fetching thread panicked");
+    if let Some(n) = queue.running.get_mut(host) {
+        *n -= 1;
+    }
+    cvar.notify_all();
+}
+
+/// Retrieves every job with `client` within `limits`, retrying as told by
+/// `Job::retry` but not past `deadline`, after which the jobs not started yet
+/// fail without being retrieved. Calls `each` on the calling thread with the
+/// job and its result, in the order of `jobs`, as soon as the job and all the
+/// ones before it are done. Stops at the first `Err` returned by `each`. See
+/// `upstream::to_source` for the results.
+///
+/// # Errors
+///
+/// Returns the first `Err` returned by `each`.
+pub fn fetch_all<F, E>(
+    client: &Client,
+    jobs: Vec<Job>,
+    limits: Limits,
+    deadline: Option<Instant>,
+    mut each: F,
+) -> Result<(), E>
+where
+    F: FnMut(&Job, Result<Retrieved, util::Error>) -> Result<(), E>,
+{
+    let client = Arc::new(Client::clone(client));
+    let jobs = Arc::new(jobs);
+    let shared: Shared = Arc::new((
+        Mutex::new(Queue {
+            pending: (0..jobs.len()).collect(),
+            running: HashMap::new(),
+        }),
+        Condvar::new(),
+    ));
+    let per_host = limits.per_host.max(1);
+    let (tx, rx) = mpsc::channel();
+    let workers: Vec<_> = (0..limits.total.max(1).min(jobs.len()))
+        .map(|_| {
+            let client = Arc::clone(&client);
+            let jobs = Arc::clone(&jobs);
+            let shared = Arc::clone(&shared);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                while let Some(taken) = take(&shared, &jobs, per_host, deadline) {
+                    let (i, result) = match taken {
+                        Taken::Expired(i) => (
+                            i,
+                            Err(util::Error::FetchFailed {
+                                url: String::clone(&jobs[i].url),
+                                source: "run time exceeded".into(),
+                            }),
+                        ),
+                        Taken::Run(i) => {
+                            let job = &jobs[i];
+                            let result = retry::with_backoff(&job.retry, deadline, || {
+                                to_source(
+                                    &client,
+                                    &job.url,
+                                    job.validators.as_ref(),
+                                    &job.options,
+                                    job.filter.as_deref(),
+                                )
+                            });
+                            finish(&shared, &host(&job.url));
+                            (i, result)
+                        }
+                    };
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect();
+    drop(tx);
+    let mut ready = BTreeMap::new();
+    let mut next = 0;
+    let mut outcome = Ok(());
+    for (i, result) in &rx {
+        ready.insert(i, result);
+        while let Some(result) = ready.remove(&next) {
+            outcome = each(&jobs[next], result);
+            next += 1;
+            if outcome.is_err() {
+                break;
+            }
+        }
+        if outcome.is_err() {
+            break;
+        }
+    }
+    // let the workers notice that no one listens anymore.
+    drop(rx);
+    for worker in workers {
+        let _ = worker.join();
+    }
+    outcome?;
+    // only reached with missing results if a worker panicked.
+    for (i, job) in jobs.iter().enumerate().skip(next) {
+        let result = ready.remove(&i).unwrap_or_else(|| {
+            Err(util::Error::FetchFailed {
+                url: String::clone(&job.url),
+                source: "the fetching thread panicked".into(),
+            })
+        });
+        each(job, result)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_of_url() {
+        assert_eq!(host("https://Example.com/rss.xml"), "example.com");
+        assert_eq!(
+            host("http://user:pw@example.com:8080?x"),
+            "example.com:8080"
+        );
+        assert_eq!(host("quinoa42.github.io/rss.xml"), "quinoa42.github.io");
+    }
+
+    #[test]
+    fn results_in_order() {
+        let jobs: Vec<_> = (0..16)
+            .map(|i| Job {
+                alias: format!("{i:02}"),
+                url: format!("not a url {}", i % 3),
+                validators: None,
+                retry: Policy::default(),
+                options: Options::default(),
+                filter: None,
+            })
+            .collect();
+        let mut seen = vec![];
+        fetch_all(
+            &Client::default(),
+            jobs,
+            Limits {
+                total: 4,
+                per_host: 1,
+            },
+            None,
+            |job, result| {
+                assert!(result.is_err());
+                seen.push(String::clone(&job.alias));
+                Ok::<(), ()>(())
+            },
+        )
+        .unwrap();
+        let expected: Vec<_> = (0..16).map(|i| format!("{i:02}")).collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn run_time_exceeded() {
+        let jobs: Vec<_> = (0..4)
+            .map(|i| Job {
+                alias: format!("{i}"),
+                url: format!("https://example.com/{i}.xml"),
+                validators: None,
+                retry: Policy::default(),
+                options: Options::default(),
+                filter: None,
+            })
+            .collect();
+        let mut seen = 0;
+        fetch_all(
+            &Client::default(),
+            jobs,
+            Limits::default(),
+            Some(Instant::now()),
+            |_, result| {
+                let e = result.err().unwrap();
+                assert!(e.to_string().ends_with("run time exceeded"));
+                seen += 1;
+                Ok::<(), ()>(())
+            },
+        )
+        .unwrap();
+        assert_eq!(seen, 4);
+    }
+}
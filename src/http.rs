@@ -0,0 +1,733 @@
+//! Code that talks HTTP to the servers of the feeds.
+
+use crate::metadata::Validators;
+use crate::util;
+use brotli_decompressor::Decompressor;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::convert::TryFrom;
+use std::env;
+use std::io::Read;
+use std::time::Duration;
+use url::Url;
+
+/// How the HTTP requests are made.
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// how long to wait for the connection to a server.
+    pub connect_timeout: Duration,
+    /// how long to wait for each read from a server.
+    pub read_timeout: Duration,
+    /// how many redirects to follow before giving up.
+    pub max_redirects: u32,
+    /// the `User-Agent` sent unless a request sets its own.
+    pub user_agent: String,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Duration::from_secs(30),
+            max_redirects: 5,
+            user_agent: format!("rsst/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+}
+
+/// What a server answered about a feed.
+#[derive(Debug)]
+pub enum Content {
+    /// the feed did not change since the given validators were returned.
+    NotModified,
+    /// the feed, decompressed, along with its new validators.
+    Body(Vec<u8>, Validators),
+}
+
+/// The final answer to a request, after following redirects.
+#[derive(Debug)]
+pub struct Response {
+    /// what the server answered.
+    pub content: Content,
+    /// where the feed has permanently moved to, if the server said so.
+    pub moved_to: Option<String>,
+}
+
+/// How to make the requests for a single feed.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// extra headers to send, as `(name, value)` pairs.
+    pub headers: Vec<(String, String)>,
+    /// the proxy to go through, such as `"socks5://127.0.0.1:9050"`, or
+    /// `"none"` to go without one.
+    pub proxy: Option<String>,
+    /// the proxy to go through unless `proxy` is given, for the hosts not in
+    /// `NO_PROXY`.
+    pub global_proxy: Option<String>,
+}
+
+/// The headers not to send to another host than the one they were given for.
+const CREDENTIALS: [&str; 3] = ["Authorization", "Cookie", "Proxy-Authorization"];
+
+/// Removes the `CREDENTIALS` from `headers`.
+fn remove_credentials(headers: &mut Vec<(String, String)>) {
+    headers.retain(|(name, _)| !CREDENTIALS.iter().any(|c| c.eq_ignore_ascii_case(name)));
+}
+
+impl Options {
+    /// Returns the options to follow a link from `from` to `to` with, which
+    /// leave out the credentials if `to` is on another host.
+    #[must_use]
+    pub fn follow(&self, from: &str, to: &str) -> Self {
+        let mut options = self.clone();
+        if origin(to) != origin(from) {
+            remove_credentials(&mut options.headers);
+        }
+        options
+    }
+}
+
+/// Returns the value of the `Authorization` header for HTTP Basic
+/// authentication as `username` with `password`.
+#[must_use]
+pub fn basic_auth(username: &str, password: &str) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let input = format!("{username}:{password}").into_bytes();
+    let mut encoded = String::from("Basic ");
+    for chunk in input.chunks(3) {
+        let bytes = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Returns whether `url` matches `no_proxy`, a list of hosts and domains
+/// separated by commas, as in `NO_PROXY`. `*` matches every host, and an
+/// entry may end with a `:port`.
+fn is_excluded(url: &str, no_proxy: &str) -> bool {
+    let Some((host, port)) = origin(url) else {
+        return false;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            if entry == "*" {
+                return true;
+            }
+            let (name, entry_port) = match entry.rfind(':') {
+                Some(i) if entry.starts_with('[') || !entry[..i].contains(':') => {
+                    (&entry[..i], entry[i + 1..].parse().ok())
+                }
+                _ => (entry, None),
+            };
+            if entry_port.is_some() && entry_port != port {
+                return false;
+            }
+            let name = name
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .trim_start_matches("*.")
+                .trim_start_matches('.')
+                .to_lowercase();
+            host.eq_ignore_ascii_case(&name) || host.to_lowercase().ends_with(&format!(".{name}"))
+        })
+}
+
+/// Returns the proxy to retrieve `url` through, given `var` to read the
+/// environment. See `proxy_for`.
+fn proxy_with<F>(
+    url: &str,
+    per_source: Option<&str>,
+    global: Option<&str>,
+    var: F,
+) -> Option<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let configured = |proxy: &str| Some(String::from(proxy)).filter(|p| p != "none");
+    if let Some(proxy) = per_source {
+        return configured(proxy);
+    }
+    // the lowercase forms win, as they do for most tools.
+    let var = |name: &str| {
+        var(&name.to_lowercase())
+            .or_else(|| var(name))
+            .filter(|v| !v.trim().is_empty())
+    };
+    if var("NO_PROXY").is_some_and(|list| is_excluded(url, &list)) {
+        return None;
+    }
+    if let Some(proxy) = global {
+        return configured(proxy);
+    }
+    match Url::parse(url).as_ref().map(Url::scheme) {
+        Ok("https") => var("HTTPS_PROXY"),
+        Ok("http") => var("HTTP_PROXY"),
+        _ => None,
+    }
+}
+
+/// Returns the proxy to retrieve `url` through: `per_source` if given,
+/// otherwise `global`, or else the one in `HTTPS_PROXY` or `HTTP_PROXY`
+/// depending on the scheme of `url`. `"none"` means going without a proxy.
+/// Only `per_source` applies to the hosts in `NO_PROXY`.
+#[must_use]
+pub fn proxy_for(url: &str, per_source: Option<&str>, global: Option<&str>) -> Option<String> {
+    proxy_with(url, per_source, global, |name| env::var(name).ok())
+}
+
+/// Returns the host and port of `url`, if it has any.
+fn origin(url: &str) -> Option<(String, Option<u16>)> {
+    let url = Url::parse(url).ok()?;
+    Some((String::from(url.host_str()?), url.port_or_known_default()))
+}
+
+/// Wraps the `source` of a failure to retrieve `url`.
+fn failed<E>(url: &str, source: E) -> util::Error
+where
+    E: Into<util::Cause>,
+{
+    util::Error::FetchFailed {
+        url: String::from(url),
+        source: source.into(),
+    }
+}
+
+/// Reads the body of `response`, undoing its `Content-Encoding`.
+fn read_body(url: &str, response: ureq::Response) -> Result<Vec<u8>, util::Error> {
+    let encoding = response
+        .header("Content-Encoding")
+        .unwrap_or("identity")
+        .trim()
+        .to_lowercase();
+    let reader = response.into_reader();
+    let mut reader: Box<dyn Read> = match encoding.as_str() {
+        "identity" => Box::new(reader),
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(reader)),
+        "deflate" => Box::new(ZlibDecoder::new(reader)),
+        "br" => Box::new(Decompressor::new(reader, 4096)),
+        v => return Err(failed(url, format!("unknown Content-Encoding `{v}`"))),
+    };
+    let mut body = vec![];
+    match reader.read_to_end(&mut body) {
+        Ok(_) => Ok(body),
+        Err(e) => Err(failed(url, e)),
+    }
+}
+
+/// Returns the number of days between 1970-01-01 and the given date of the
+/// proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Parses an HTTP date such as `Sun, 06 Nov 1994 08:49:37 GMT` into seconds
+/// since the Unix epoch.
+fn parse_http_date(value: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let fields: Vec<_> = value.split_whitespace().collect();
+    if fields.len() != 6 || fields[5] != "GMT" {
+        return None;
+    }
+    // small enough for `days_from_civil` not to overflow.
+    let day = i64::from(fields[1].parse::<i32>().ok()?);
+    let month = i64::try_from(MONTHS.iter().position(|&m| m == fields[2])?).ok()? + 1;
+    let year = i64::from(fields[3].parse::<i32>().ok()?);
+    let time: Vec<i64> = fields[4]
+        .split(':')
+        .map(str::parse)
+        .collect::<Result<_, _>>()
+        .ok()?;
+    if time.len() != 3 {
+        return None;
+    }
+    days_from_civil(year, month, day)
+        .checked_mul(86_400)?
+        .checked_add(time[0].checked_mul(3_600)?)?
+        .checked_add(time[1].checked_mul(60)?)?
+        .checked_add(time[2])
+}
+
+/// Parses the `Retry-After` header `value`, which is either a number of
+/// seconds or an HTTP date, into how long to wait from `now`.
+fn retry_after(value: &str, now: i64) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => parse_http_date(value)
+            .map(|t| Duration::from_secs(u64::try_from(t.saturating_sub(now)).unwrap_or(0))),
+    }
+}
+
+impl Client {
+    /// Sends a single request for `url` with `agent` and the extra `headers`,
+    /// without following redirects.
+    fn send(
+        &self,
+        agent: &ureq::Agent,
+        url: &str,
+        validators: Option<&Validators>,
+        headers: &[(String, String)],
+    ) -> Result<ureq::Response, util::Error> {
+        let mut request = agent
+            .get(url)
+            .set("User-Agent", &self.user_agent)
+            .set("Accept-Encoding", "gzip, deflate, br");
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        if let Some(validators) = validators {
+            if let Some(etag) = &validators.etag {
+                request = request.set("If-None-Match", etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.set("If-Modified-Since", last_modified);
+            }
+        }
+        match request.call() {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+            Err(e) => Err(failed(url, e)),
+        }
+    }
+
+    /// Returns an agent to retrieve `url` with, through the proxy chosen by
+    /// `proxy_with` from `options` and `var`.
+    fn agent<F>(&self, url: &str, options: &Options, var: F) -> Result<ureq::Agent, util::Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut builder = ureq::AgentBuilder::new()
+            .timeout_connect(self.connect_timeout)
+            .timeout_read(self.read_timeout)
+            .redirects(0);
+        let proxy = proxy_with(
+            url,
+            options.proxy.as_deref(),
+            options.global_proxy.as_deref(),
+            var,
+        );
+        if let Some(proxy) = proxy {
+            builder = builder.proxy(ureq::Proxy::new(&proxy).map_err(|e| failed(url, e))?);
+        }
+        Ok(builder.build())
+    }
+
+    /// Retrieves `url` as told by `options`, following redirects. If
+    /// `validators` are given, asks the server to answer `Content::NotModified`
+    /// when the feed did not change since. Credentials in `options.headers`
+    /// are not sent to other hosts than the one of `url`, and the proxy is
+    /// chosen again for each of them, as `proxy_for` does.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the request fails, the server answers with an error status, or
+    /// there are too many redirects.
+    pub fn get(
+        &self,
+        url: &str,
+        validators: Option<&Validators>,
+        options: &Options,
+    ) -> Result<Response, util::Error> {
+        self.get_with(url, validators, options, |name| env::var(name).ok())
+    }
+
+    /// Same as `get`, given `var` to read the environment.
+    fn get_with<F>(
+        &self,
+        url: &str,
+        validators: Option<&Validators>,
+        options: &Options,
+        var: F,
+    ) -> Result<Response, util::Error>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut current = String::from(url);
+        let mut headers = options.headers.clone();
+        let mut moved_to = None;
+        let mut permanent = true;
+        let mut redirects = 0;
+        loop {
+            let agent = self.agent(&current, options, &var)?;
+            let response = self.send(&agent, &current, validators, &headers)?;
+            let status = response.status();
+            match status {
+                301 | 302 | 303 | 307 | 308 => {
+                    if redirects == self.max_redirects {
+                        return Err(failed(url, format!("more than {redirects} redirects")));
+                    }
+                    redirects += 1;
+                    let Some(location) = response.header("Location") else {
+                        return Err(failed(url, format!("{status} without a Location")));
+                    };
+                    let next = match Url::parse(&current).and_then(|base| base.join(location)) {
+                        Ok(v) => String::from(v),
+                        Err(e) => return Err(failed(url, e)),
+                    };
+                    // only a chain of permanent redirects from `url` moves the feed.
+                    permanent = permanent && (status == 301 || status == 308);
+                    if permanent {
+                        moved_to = Some(String::clone(&next));
+                    }
+                    if origin(&next) != origin(url) {
+                        remove_credentials(&mut headers);
+                    }
+                    current = next;
+                }
+                304 => {
+                    return Ok(Response {
+                        content: Content::NotModified,
+                        moved_to,
+                    })
+                }
+                200..=299 => {
+                    let validators = Validators {
+                        etag: response.header("ETag").map(String::from),
+                        last_modified: response.header("Last-Modified").map(String::from),
+                    };
+                    return Ok(Response {
+                        content: Content::Body(read_body(url, response)?, validators),
+                        moved_to,
+                    });
+                }
+                status => {
+                    let retry_after = response
+                        .header("Retry-After")
+                        .and_then(|v| retry_after(v, util::now()));
+                    return Err(util::Error::Http {
+                        url: current,
+                        status,
+                        retry_after,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers one connection per item of `responses` on a local port.
+    /// Returns the address to request and a handle to the requests received.
+    fn serve(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        serve_bytes(responses.into_iter().map(String::into_bytes).collect())
+    }
+
+    /// Same as `serve`, with raw `responses`.
+    fn serve_bytes(responses: Vec<Vec<u8>>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rss.xml", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                stream.write_all(&response).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn conditional_request() {
+        let (url, handle) = serve(vec![
+            String::from(
+                "HTTP/1.1 200 OK\r\nETag: \"42\"\r\nLast-Modified: Sat, 01 Jun 2019 00:00:00 GMT\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfeed",
+            ),
+            String::from("HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n"),
+        ]);
+        let client = Client::default();
+        let validators = match client.get(&url, None, &Options::default()).unwrap().content {
+            Content::Body(body, validators) => {
+                assert_eq!(body, b"feed");
+                validators
+            }
+            r @ Content::NotModified => panic!("unexpected response {:?}", r),
+        };
+        assert_eq!(validators.etag, Some(String::from("\"42\"")));
+        match client
+            .get(&url, Some(&validators), &Options::default())
+            .unwrap()
+            .content
+        {
+            Content::NotModified => (),
+            r @ Content::Body(..) => panic!("unexpected response {:?}", r),
+        }
+        let requests = handle.join().unwrap();
+        assert!(!requests[0].contains("If-None-Match"));
+        assert!(requests[1].contains("If-None-Match: \"42\""));
+        assert!(requests[1].contains("If-Modified-Since: Sat, 01 Jun 2019 00:00:00 GMT"));
+    }
+
+    #[test]
+    fn error_status() {
+        let (url, handle) = serve(vec![String::from(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 5\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        )]);
+        match Client::default().get(&url, None, &Options::default()) {
+            Err(util::Error::Http {
+                status,
+                retry_after,
+                ..
+            }) => {
+                assert_eq!(status, 503);
+                assert_eq!(retry_after, Some(Duration::from_secs(5)));
+            }
+            r => panic!("unexpected response {:?}", r),
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn permanent_redirect() {
+        let (url, handle) = serve(vec![
+            String::from(
+                "HTTP/1.1 301 Moved Permanently\r\nLocation: /new.xml\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            ),
+            String::from(
+                "HTTP/1.1 302 Found\r\nLocation: /tmp.xml\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            ),
+            String::from("HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfeed"),
+        ]);
+        let response = Client::default()
+            .get(&url, None, &Options::default())
+            .unwrap();
+        assert_eq!(response.moved_to, Some(url.replace("rss.xml", "new.xml")));
+        let requests = handle.join().unwrap();
+        assert!(requests[1].starts_with("GET /new.xml "));
+        assert!(requests[2].starts_with("GET /tmp.xml "));
+    }
+
+    #[test]
+    fn too_many_redirects() {
+        let redirect = String::from(
+            "HTTP/1.1 307 Temporary Redirect\r\nLocation: /rss.xml\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        let (url, handle) = serve(vec![redirect; 3]);
+        let client = Client {
+            max_redirects: 2,
+            ..Client::default()
+        };
+        assert!(client.get(&url, None, &Options::default()).is_err());
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn gzip_body() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"feed").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            gzipped.len()
+        )
+        .into_bytes();
+        response.extend(gzipped);
+        let (url, handle) = serve_bytes(vec![response]);
+        match Client::default()
+            .get(&url, None, &Options::default())
+            .unwrap()
+            .content
+        {
+            Content::Body(body, _) => assert_eq!(body, b"feed"),
+            r @ Content::NotModified => panic!("unexpected response {:?}", r),
+        }
+        assert!(handle.join().unwrap()[0].contains("Accept-Encoding: gzip"));
+    }
+
+    #[test]
+    fn headers_and_credentials() {
+        assert_eq!(
+            basic_auth("Aladdin", "open sesame"),
+            "Basic QWxhZGRpbjpvcGVuIHNlc2FtZQ=="
+        );
+        assert_eq!(basic_auth("a", "b"), "Basic YTpi");
+        let ok =
+            String::from("HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfeed");
+        let (elsewhere, elsewhere_handle) = serve(vec![String::clone(&ok)]);
+        let (url, handle) = serve(vec![
+            ok,
+            format!(
+                "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                elsewhere
+            ),
+        ]);
+        let options = Options {
+            headers: vec![
+                (String::from("User-Agent"), String::from("Mozilla/5.0")),
+                (String::from("Authorization"), basic_auth("a", "b")),
+            ],
+            ..Options::default()
+        };
+        let client = Client::default();
+        client.get(&url, None, &options).unwrap();
+        client.get(&url, None, &options).unwrap();
+        let requests = handle.join().unwrap();
+        assert!(requests[0].contains("User-Agent: Mozilla/5.0"));
+        assert!(requests[0].contains("Authorization: Basic YTpi"));
+        assert!(requests[1].contains("Authorization: Basic YTpi"));
+        // the port differs, so the redirect leads to another server.
+        let redirected = &elsewhere_handle.join().unwrap()[0];
+        assert!(redirected.contains("User-Agent: Mozilla/5.0"));
+        assert!(!redirected.contains("Authorization"));
+    }
+
+    #[test]
+    fn choose_proxy() {
+        let env = |name: &str| match name {
+            "https_proxy" => Some(String::from("http://proxy.corp:3128")),
+            "NO_PROXY" => Some(String::from("localhost, .intranet.corp,example.org:8080")),
+            _ => None,
+        };
+        let proxy = |url, per_source, global| proxy_with(url, per_source, global, env);
+        let corp = Some(String::from("http://proxy.corp:3128"));
+        assert_eq!(proxy("https://example.com/rss.xml", None, None), corp);
+        assert_eq!(proxy("http://example.com/rss.xml", None, None), None);
+        assert_eq!(
+            proxy("https://news.intranet.corp/rss.xml", None, None),
+            None
+        );
+        assert_eq!(proxy("http://example.org:8080/rss.xml", None, None), None);
+        assert_eq!(proxy("https://example.org/rss.xml", None, None), corp);
+        let tor = Some("socks5://127.0.0.1:9050");
+        assert_eq!(
+            proxy("http://localhost/rss.xml", tor, None),
+            tor.map(String::from)
+        );
+        assert_eq!(proxy("http://localhost/rss.xml", None, tor), None);
+        assert_eq!(
+            proxy("http://example.com/rss.xml", None, tor),
+            tor.map(String::from)
+        );
+        assert_eq!(
+            proxy("https://example.com/rss.xml", Some("none"), tor),
+            None
+        );
+    }
+
+    #[test]
+    fn through_http_proxy() {
+        let (url, handle) = serve(vec![String::from(
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfeed",
+        )]);
+        let options = Options {
+            proxy: Some(url.replace("/rss.xml", "")),
+            ..Options::default()
+        };
+        match Client::default()
+            .get("http://feeds.example.com/rss.xml", None, &options)
+            .unwrap()
+            .content
+        {
+            Content::Body(body, _) => assert_eq!(body, b"feed"),
+            r @ Content::NotModified => panic!("unexpected response {:?}", r),
+        }
+        let requests = handle.join().unwrap();
+        assert!(requests[0].starts_with("GET http://feeds.example.com/rss.xml "));
+    }
+
+    #[test]
+    fn proxy_per_redirect() {
+        let (direct, direct_handle) = serve(vec![String::from(
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\nfeed",
+        )]);
+        let (proxy, proxy_handle) = serve(vec![format!(
+            "HTTP/1.1 302 Found\r\nLocation: {direct}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+        )]);
+        let options = Options {
+            global_proxy: Some(proxy.replace("/rss.xml", "")),
+            ..Options::default()
+        };
+        let env = |name: &str| Some(String::from("127.0.0.1")).filter(|_| name == "NO_PROXY");
+        Client::default()
+            .get_with("http://feeds.example.com/rss.xml", None, &options, env)
+            .unwrap();
+        let requests = proxy_handle.join().unwrap();
+        assert!(requests[0].starts_with("GET http://feeds.example.com/rss.xml "));
+        // the redirect leads to a host in `NO_PROXY`, reached directly.
+        assert!(direct_handle.join().unwrap()[0].starts_with("GET /rss.xml "));
+    }
+
+    #[test]
+    fn retry_after_header() {
+        assert_eq!(retry_after(" 120 ", 0), Some(Duration::from_mins(2)));
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(784_111_777)
+        );
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:37 GMT", 784_111_700),
+            Some(Duration::from_secs(77))
+        );
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:37 GMT", 784_111_800),
+            Some(Duration::from_secs(0))
+        );
+        assert_eq!(retry_after("soon", 0), None);
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 9223372036854775807:00:00 GMT"),
+            None
+        );
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 99999999999 08:49:37 GMT"),
+            None
+        );
+        assert_eq!(
+            retry_after("Sun, 06 Nov 2147483647 08:49:37 GMT", i64::MIN),
+            Some(Duration::from_secs(u64::MAX >> 1))
+        );
+    }
+
+    #[test]
+    fn read_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/rss.xml", listener.local_addr().unwrap());
+        let client = Client {
+            read_timeout: Duration::from_millis(100),
+            ..Client::default()
+        };
+        // the connection is accepted by the OS but never answered.
+        match client.get(&url, None, &Options::default()) {
+            Err(util::Error::FetchFailed { .. }) => (),
+            r => panic!("unexpected response {:?}", r),
+        }
+        drop(listener);
+    }
+}
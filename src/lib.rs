@@ -10,6 +10,14 @@
 pub mod cli;
 pub mod config;
 pub mod downstream;
+pub mod fetch;
+pub mod http;
+pub mod lock;
 pub mod metadata;
+pub mod opml;
+pub mod retry;
+pub mod schedule;
+pub mod state;
 pub mod upstream;
 pub mod util;
+pub mod validate;
@@ -0,0 +1,176 @@
+//! Code that keeps concurrent runs of `RSSt` from touching the same files.
+
+use crate::util;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// The name of the lock file inside the metadata dir.
+pub const LOCK_FILE: &str = "rsst.lock";
+
+/// The run lock. Released when dropped.
+#[derive(Debug)]
+pub struct Lock {
+    /// the lock file owned by this run.
+    path: PathBuf,
+    /// the lock file, open for as long as the lock is held.
+    _file: File,
+}
+
+/// Takes an exclusive lock on `file`, blocking until it is free if `wait`
+/// is set. Returns whether the lock was taken. The system releases it when
+/// the file is closed, including when the process dies.
+#[cfg(unix)]
+fn flock(file: &File, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let operation = if wait {
+        libc::LOCK_EX
+    } else {
+        libc::LOCK_EX | libc::LOCK_NB
+    };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            ErrorKind::Interrupted => (),
+            ErrorKind::WouldBlock => return Ok(false),
+            _ => return Err(e),
+        }
+    }
+}
+
+/// Takes an exclusive lock on `file`. Not supported outside unix, where
+/// runs are not kept from overlapping.
+#[cfg(not(unix))]
+fn flock(_file: &File, _wait: bool) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Returns whether `file` is still the one at `path`, which a run that
+/// released the lock may have removed in the meantime.
+#[cfg(unix)]
+fn is_current(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Returns whether `file` is still the one at `path`.
+#[cfg(not(unix))]
+fn is_current(_file: &File, path: &Path) -> bool {
+    path.exists()
+}
+
+/// Returns the PID of the run holding the lock file at `path`, if it could
+/// be read.
+fn holder(path: &Path) -> Option<u32> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .filter(|&pid| pid != 0)
+}
+
+impl Lock {
+    /// Takes the run lock in `metadata_dir`. If another run holds it, blocks
+    /// until it is released when `wait` is set, or fails with
+    /// `util::Error::Locked` otherwise. The PID of the run is written in the
+    /// lock file, to tell which one holds it.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the lock file can't be opened or written, or is held and
+    /// `wait` is not set.
+    pub fn acquire(metadata_dir: &Path, wait: bool) -> Result<Self, util::Error> {
+        let path = metadata_dir.join(LOCK_FILE);
+        let failed = |e| util::Error::io(&path, e);
+        let mut announced = false;
+        loop {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)
+                .map_err(failed)?;
+            if !flock(&file, false).map_err(failed)? {
+                if !wait {
+                    let pid = holder(&path);
+                    return Err(util::Error::Locked { path, pid });
+                }
+                if !announced {
+                    eprintln!(
+                        "waiting for the rsst run holding {} to finish ...",
+                        path.display()
+                    );
+                    announced = true;
+                }
+                flock(&file, true).map_err(failed)?;
+            }
+            // the run that held the lock removed the file when it was done,
+            // so another run may be holding a new one already.
+            if !is_current(&file, &path) {
+                continue;
+            }
+            file.set_len(0).map_err(failed)?;
+            write!(file, "{}", std::process::id()).map_err(failed)?;
+            return Ok(Self { path, _file: file });
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // removed while still locked, so that no run can lock it in between.
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{remove_dir_all, write};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn exclusive() {
+        let dir = util::scratch_dir("lock-exclusive");
+        let lock = Lock::acquire(&dir, false).unwrap();
+        match Lock::acquire(&dir, false).unwrap_err() {
+            util::Error::Locked { pid, .. } => assert_eq!(pid, Some(std::process::id())),
+            e => panic!("unexpected error {:?}", e),
+        }
+        drop(lock);
+        assert!(!dir.join(LOCK_FILE).exists());
+        assert!(Lock::acquire(&dir, false).is_ok());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stale() {
+        let dir = util::scratch_dir("lock-stale");
+        // left behind by a run that crashed, so no longer locked.
+        write(dir.join(LOCK_FILE), "1").unwrap();
+        assert!(Lock::acquire(&dir, false).is_ok());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait() {
+        let dir = util::scratch_dir("lock-wait");
+        let lock = Lock::acquire(&dir, false).unwrap();
+        let waiting = {
+            let dir = PathBuf::clone(&dir);
+            thread::spawn(move || Lock::acquire(&dir, true).map(drop))
+        };
+        thread::sleep(Duration::from_millis(100));
+        drop(lock);
+        waiting.join().unwrap().unwrap();
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,11 +1,21 @@
 //! the bin for `RSSt`.
 
 use rsst::cli;
+use std::process;
 use structopt::StructOpt;
 
-/// Attempts to retrieve feeds. Prints errors if encounter any.
+/// Attempts to retrieve feeds. Prints errors if encounter any, then exits
+/// with the matching `cli::Status`.
 pub fn main() {
-    if let Err(e) = cli::run(cli::Opt::from_args()) {
-        eprintln!("{}", e);
-    }
+    let status = match cli::run(&cli::Opt::from_args()) {
+        Ok(report) => {
+            report.print_summary();
+            report.status()
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            e.status
+        }
+    };
+    process::exit(status as i32);
 }
@@ -1,40 +1,162 @@
 //! Code that manipulates the metadata file.
 
+use crate::schedule::Schedule;
 use crate::util;
 use serde::{Deserialize, Serialize};
-use serde_json;
-use std::path::PathBuf;
+use serde_json::{self, Value};
+use std::path::{Path, PathBuf};
+
+/// The version of the metadata file format written by this build.
+pub const VERSION: u64 = 1;
+
+/// Upgrades a collection from the version of its index to the next one.
+const MIGRATIONS: [fn(Value) -> Value; VERSION as usize] = [v0_to_v1];
 
 /// A metadata entry for a feed.
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
 pub struct Metadata {
     /// the title of this feed.
     pub title: String,
     /// the checksum in md5 that marks the last newest article.
     pub checksum: String,
+    /// when this feed was last retrieved, in seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fetched: Option<i64>,
+    /// when this feed says it is worth retrieving.
+    #[serde(default, skip_serializing_if = "Schedule::is_empty")]
+    pub schedule: Schedule,
+}
+
+/// The HTTP cache validators last returned by the server of a feed.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Default)]
+pub struct Validators {
+    /// the value of the `ETag` header.
+    pub etag: Option<String>,
+    /// the value of the `Last-Modified` header.
+    pub last_modified: Option<String>,
 }
 
 /// A collection that maps alias to metadata for each feed.
-#[derive(Deserialize, Serialize, Default)]
+#[derive(Deserialize, Serialize)]
 pub struct Collection {
+    /// the format version this collection was saved in.
+    /// Files written before versioning are version `0`.
+    #[serde(default)]
+    pub version: u64,
     pub metadata: std::collections::BTreeMap<String, Metadata>,
+    /// HTTP cache validators of each feed, keyed by alias.
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub validators: std::collections::BTreeMap<String, Validators>,
+}
+
+impl Default for Collection {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            metadata: std::collections::BTreeMap::default(),
+            validators: std::collections::BTreeMap::default(),
+        }
+    }
+}
+
+/// Version `1` only introduced the `version` field itself.
+fn v0_to_v1(mut value: Value) -> Value {
+    if let Some(map) = value.as_object_mut() {
+        map.insert(String::from("version"), Value::from(1));
+    }
+    value
+}
+
+/// Upgrades the raw `value` of a collection to `VERSION`.
+/// Fails with the version of `value` if it is newer than `VERSION`.
+fn migrate(mut value: Value) -> Result<Value, u64> {
+    let from = value.get("version").and_then(Value::as_u64).unwrap_or(0);
+    if from > VERSION {
+        return Err(from);
+    }
+    for migration in &MIGRATIONS[from as usize..] {
+        value = migration(value);
+    }
+    Ok(value)
 }
 
-/// Try deserializing the file at the given `PathBuf` into a `Collection`.
-pub fn get(name: PathBuf) -> Result<Collection, util::Error> {
+/// Try deserializing the file at the given `Path` into a `Collection`.
+pub fn get(name: &Path) -> Result<Collection, util::Error> {
     let output = util::to_string(name)?;
-    match serde_json::from_str(&output) {
+    let parse_error = |e: serde_json::Error| {
+        let mut message = e.to_string();
+        if e.line() > 0 {
+            // the position is reported on its own.
+            if let Some(i) = message.rfind(" at line ") {
+                message.truncate(i);
+            }
+        }
+        util::Error::ParseFailed {
+            path: PathBuf::from(name),
+            line: Some(e.line()).filter(|&v| v > 0),
+            column: Some(e.column()).filter(|&v| v > 0),
+            message,
+            source: Some(Box::new(e)),
+        }
+    };
+    let value = match serde_json::from_str(&output) {
+        Ok(v) => match migrate(v) {
+            Ok(v) => v,
+            Err(version) => {
+                return Err(util::Error::TooNew {
+                    path: PathBuf::from(name),
+                    version,
+                })
+            }
+        },
+        Err(e) => return Err(parse_error(e)),
+    };
+    match serde_json::from_value(value) {
         Ok(v) => Ok(v),
-        Err(_) => Err(util::Error::ParseFailed),
+        Err(e) => Err(parse_error(e)),
+    }
+}
+
+/// Returns the alias that ends right before the `:` at `colon` in `text`.
+fn key_before(text: &str, colon: usize) -> Option<&str> {
+    let end = text[..colon].trim_end();
+    if !end.ends_with('"') {
+        return None;
+    }
+    let end = end.len() - 1;
+    let start = text[..end].rfind('"')? + 1;
+    Some(&text[start..end])
+}
+
+/// Recovers every `"alias": { "title": ..., "checksum": ... }` entry that
+/// can still be parsed from the (possibly truncated or mangled) `text`.
+#[must_use]
+pub fn salvage(text: &str) -> Collection {
+    let mut collection = Collection::default();
+    for (colon, _) in text.match_indices(':') {
+        let rest = &text[colon + 1..];
+        let brace = colon + 1 + (rest.len() - rest.trim_start().len());
+        if !text[brace..].starts_with('{') {
+            continue;
+        }
+        let mut de = serde_json::Deserializer::from_str(&text[brace..]);
+        if let (Some(alias), Ok(metadata)) =
+            (key_before(text, colon), Metadata::deserialize(&mut de))
+        {
+            collection.metadata.insert(String::from(alias), metadata);
+        }
     }
+    collection
 }
 
 impl Collection {
     /// Try Serializing `self` into a `String`.
-    pub fn put(self) -> Result<String, util::Error> {
-        match serde_json::to_string(&self) {
+    pub fn put(&self) -> Result<String, util::Error> {
+        match serde_json::to_string(self) {
             Ok(s) => Ok(s),
-            Err(_) => Err(util::Error::DumpFailed),
+            Err(e) => Err(util::Error::DumpFailed {
+                source: Box::new(e),
+            }),
         }
     }
 }
@@ -62,7 +184,55 @@ mod tests {
             c.metadata.get("simple"),
             Some(&Metadata {
                 title: String::from("hello, world"),
-                checksum: String::from("42")
+                checksum: String::from("42"),
+                fetched: None,
+                schedule: Schedule::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_schedule() {
+        let c: Collection = serde_json::from_str(
+            r#"{"version":1,"metadata":{"weekly":{"title":"w","checksum":"42","fetched":1559520000,"schedule":{"ttl":60,"skip_days":[5,6]}}}}"#,
+        )
+        .unwrap();
+        let metadata = &c.metadata["weekly"];
+        assert_eq!(metadata.fetched, Some(1_559_520_000));
+        assert_eq!(metadata.schedule.ttl, Some(60));
+        assert_eq!(metadata.schedule.skip_days, vec![5, 6]);
+        assert_eq!(metadata.schedule.update_period, None);
+    }
+
+    #[test]
+    fn migrate_unversioned() {
+        let value = migrate(serde_json::from_str(r#"{ "metadata": {} }"#).unwrap()).unwrap();
+        let c: Collection = serde_json::from_value(value).unwrap();
+        assert_eq!(c.version, VERSION);
+    }
+
+    #[test]
+    fn migrate_newer() {
+        let newer = format!(r#"{{ "version": {}, "metadata": {{}} }}"#, VERSION + 1);
+        assert_eq!(
+            migrate(serde_json::from_str(&newer).unwrap()).err(),
+            Some(VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn salvage_truncated() {
+        let c = salvage(
+            r#"{"version":1,"metadata":{"simple":{"title":"hello, world","checksum":"42"},"broken":{"title":"hel"#,
+        );
+        assert_eq!(c.metadata.len(), 1);
+        assert_eq!(
+            c.metadata.get("simple"),
+            Some(&Metadata {
+                title: String::from("hello, world"),
+                checksum: String::from("42"),
+                fetched: None,
+                schedule: Schedule::default(),
             })
         );
     }
@@ -0,0 +1,298 @@
+//! Code that reads and writes OPML subscription lists.
+
+use crate::util;
+use quick_xml::encoding::Decoder;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A feed listed in an OPML file.
+#[derive(Debug, PartialEq)]
+pub struct Feed {
+    /// the title of the feed, or its address if it has none.
+    pub title: String,
+    /// the address of the feed.
+    pub url: String,
+    /// the outlines the feed is nested in, joined by `/`, if any.
+    pub group: Option<String>,
+}
+
+/// Returns the attributes of the element `e`, decoded with `decoder` and
+/// keyed by their lowercase names.
+fn attributes(e: &BytesStart, decoder: Decoder) -> BTreeMap<String, String> {
+    e.attributes()
+        .filter_map(Result::ok)
+        .filter_map(|a| {
+            let key = String::from_utf8_lossy(a.key.as_ref()).to_lowercase();
+            let value = a.decode_and_unescape_value(decoder).ok()?;
+            Some((key, String::from(value.trim())))
+        })
+        .filter(|(_, value)| !value.is_empty())
+        .collect()
+}
+
+/// Returns the 1-based line and column of the byte at `offset` in `text`.
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text.as_bytes()[..offset.min(text.len())];
+    let line = before.split(|&b| b == b'\n').count();
+    let column = before.iter().rev().take_while(|&&b| b != b'\n').count() + 1;
+    (line, column)
+}
+
+/// Parses `text`, the content of the OPML file at `path`, into the feeds it
+/// lists, in order. Outlines that contain others without being feeds
+/// themselves are taken as groups.
+///
+/// # Errors
+///
+/// Fails with the position of the error if `text` is not valid XML.
+pub fn parse(path: &Path, text: &str) -> Result<Vec<Feed>, util::Error> {
+    let mut reader = Reader::from_str(text);
+    let mut feeds = vec![];
+    // the group each open outline starts, if it does.
+    let mut groups: Vec<Option<String>> = vec![];
+    loop {
+        let (e, empty) = match reader.read_event() {
+            Ok(Event::Start(e)) => (e, false),
+            Ok(Event::Empty(e)) => (e, true),
+            Ok(Event::End(ref e)) if e.local_name().as_ref() == b"outline" => {
+                groups.pop();
+                continue;
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => continue,
+            Err(e) => {
+                let (line, column) = position(text, reader.error_position() as usize);
+                return Err(util::Error::ParseFailed {
+                    path: PathBuf::from(path),
+                    line: Some(line),
+                    column: Some(column),
+                    message: e.to_string(),
+                    source: Some(Box::new(e)),
+                });
+            }
+        };
+        if e.local_name().as_ref() != b"outline" {
+            continue;
+        }
+        let mut attrs = attributes(&e, reader.decoder());
+        let title = attrs.remove("title").or_else(|| attrs.remove("text"));
+        match attrs.remove("xmlurl") {
+            Some(url) => {
+                let group: Vec<_> = groups.iter().flatten().map(String::as_str).collect();
+                feeds.push(Feed {
+                    title: title.unwrap_or_else(|| String::clone(&url)),
+                    url,
+                    group: Some(group.join("/")).filter(|g| !g.is_empty()),
+                });
+                if !empty {
+                    groups.push(None);
+                }
+            }
+            None if !empty => groups.push(title.map(|t| t.replace('/', "-"))),
+            None => (),
+        }
+    }
+    Ok(feeds)
+}
+
+/// The feeds of a group, and the groups nested in it, in the order they
+/// first appear.
+#[derive(Default)]
+struct Group<'a> {
+    /// the name of the group.
+    name: &'a str,
+    /// the feeds directly in the group.
+    feeds: Vec<&'a Feed>,
+    /// the groups nested in the group.
+    groups: Vec<Group<'a>>,
+}
+
+impl<'a> Group<'a> {
+    /// Adds `feed` to the group nested in `self` at `path`, creating the
+    /// missing ones.
+    fn add(&mut self, path: &[&'a str], feed: &'a Feed) {
+        match path.split_first() {
+            None => self.feeds.push(feed),
+            Some((name, rest)) => {
+                let i = if let Some(i) = self.groups.iter().position(|g| g.name == *name) {
+                    i
+                } else {
+                    self.groups.push(Group {
+                        name,
+                        ..Group::default()
+                    });
+                    self.groups.len() - 1
+                };
+                self.groups[i].add(rest, feed);
+            }
+        }
+    }
+
+    /// Writes the outlines of the feeds and groups in `self` into `writer`.
+    fn write(&self, writer: &mut Writer<Vec<u8>>) -> std::io::Result<()> {
+        for feed in &self.feeds {
+            let outline = BytesStart::new("outline").with_attributes(vec![
+                ("type", "rss"),
+                ("text", feed.title.as_str()),
+                ("title", feed.title.as_str()),
+                ("xmlUrl", feed.url.as_str()),
+            ]);
+            writer.write_event(Event::Empty(outline))?;
+        }
+        for group in &self.groups {
+            let outline = BytesStart::new("outline")
+                .with_attributes(vec![("text", group.name), ("title", group.name)]);
+            writer.write_event(Event::Start(outline))?;
+            group.write(writer)?;
+            writer.write_event(Event::End(BytesEnd::new("outline")))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes an OPML document titled `title` listing the feeds in `root` into
+/// `writer`.
+fn document(title: &str, root: &Group, writer: &mut Writer<Vec<u8>>) -> std::io::Result<()> {
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+    let opml = BytesStart::new("opml").with_attributes(vec![("version", "2.0")]);
+    writer.write_event(Event::Start(opml))?;
+    writer.write_event(Event::Start(BytesStart::new("head")))?;
+    writer.write_event(Event::Start(BytesStart::new("title")))?;
+    writer.write_event(Event::Text(BytesText::new(title)))?;
+    writer.write_event(Event::End(BytesEnd::new("title")))?;
+    writer.write_event(Event::End(BytesEnd::new("head")))?;
+    writer.write_event(Event::Start(BytesStart::new("body")))?;
+    root.write(writer)?;
+    writer.write_event(Event::End(BytesEnd::new("body")))?;
+    writer.write_event(Event::End(BytesEnd::new("opml")))
+}
+
+/// Writes `feeds` as an OPML subscription list titled `title`, nesting each
+/// feed in the outlines named by its group.
+///
+/// # Panics
+///
+/// Never panics: writing into memory does not fail.
+#[must_use]
+pub fn write(title: &str, feeds: &[Feed]) -> String {
+    let mut root = Group::default();
+    for feed in feeds {
+        let path: Vec<&str> = match &feed.group {
+            Some(group) => group.split('/').filter(|g| !g.is_empty()).collect(),
+            None => vec![],
+        };
+        root.add(&path, feed);
+    }
+    let mut writer = Writer::new_with_indent(vec![], b' ', 2);
+    document(title, &root, &mut writer).expect("failed to write into memory");
+    let mut opml = String::from_utf8(writer.into_inner()).expect("OPML is not UTF-8");
+    opml.push('\n');
+    opml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_outlines() {
+        let feeds = parse(
+            Path::new("feeds.opml"),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+            <opml version="2.0">
+              <head><title>Subscriptions</title></head>
+              <body>
+                <outline text="Example" type="rss" xmlUrl="https://example.com/rss.xml"/>
+                <outline text="Work" title="Work">
+                  <outline text="Tech">
+                    <outline title="Tom &amp; Jerry" xmlUrl="https://example.org/feed"/>
+                  </outline>
+                  <outline xmlurl="https://example.net/rss"/>
+                </outline>
+              </body>
+            </opml>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            feeds,
+            vec![
+                Feed {
+                    title: String::from("Example"),
+                    url: String::from("https://example.com/rss.xml"),
+                    group: None,
+                },
+                Feed {
+                    title: String::from("Tom & Jerry"),
+                    url: String::from("https://example.org/feed"),
+                    group: Some(String::from("Work/Tech")),
+                },
+                Feed {
+                    title: String::from("https://example.net/rss"),
+                    url: String::from("https://example.net/rss"),
+                    group: Some(String::from("Work")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_groups() {
+        let feeds = vec![
+            Feed {
+                title: String::from("Tom & Jerry"),
+                url: String::from("https://example.org/feed"),
+                group: Some(String::from("Work/Tech")),
+            },
+            Feed {
+                title: String::from("Example"),
+                url: String::from("https://example.com/rss.xml"),
+                group: None,
+            },
+            Feed {
+                title: String::from("Other"),
+                url: String::from("https://example.net/rss"),
+                group: Some(String::from("Work")),
+            },
+        ];
+        let opml = write("rsst", &feeds);
+        assert_eq!(
+            opml,
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<opml version="2.0">
+  <head>
+    <title>rsst</title>
+  </head>
+  <body>
+    <outline type="rss" text="Example" title="Example" xmlUrl="https://example.com/rss.xml"/>
+    <outline text="Work" title="Work">
+      <outline type="rss" text="Other" title="Other" xmlUrl="https://example.net/rss"/>
+      <outline text="Tech" title="Tech">
+        <outline type="rss" text="Tom &amp; Jerry" title="Tom &amp; Jerry" xmlUrl="https://example.org/feed"/>
+      </outline>
+    </outline>
+  </body>
+</opml>
+"#
+        );
+        let mut read = parse(Path::new("rsst.opml"), &opml).unwrap();
+        read.sort_by(|a, b| a.url.cmp(&b.url));
+        let mut feeds = feeds;
+        feeds.sort_by(|a, b| a.url.cmp(&b.url));
+        assert_eq!(read, feeds);
+    }
+
+    #[test]
+    fn invalid_opml() {
+        let e = parse(
+            Path::new("feeds.opml"),
+            "<opml>\n<body>\n<outline text=\"a\">\n</body>",
+        )
+        .unwrap_err();
+        match e {
+            util::Error::ParseFailed { line, .. } => assert_eq!(line, Some(4)),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Code that retries failed retrievals.
+
+use crate::util;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How a feed is retried when it could not be retrieved.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    /// how many times to retry after the first attempt.
+    pub retries: u32,
+    /// how long to wait before the first retry. Doubled for each later one.
+    pub delay: Duration,
+    /// the longest wait between two attempts, unless the server asks for more.
+    pub max_delay: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_mins(1),
+        }
+    }
+}
+
+/// Returns whether the failure `e` may go away by trying again.
+fn is_transient(e: &util::Error) -> bool {
+    match e {
+        util::Error::Http { status, .. } => {
+            matches!(status, 408 | 429 | 500 | 502 | 503 | 504)
+        }
+        util::Error::FetchFailed { source, .. } => {
+            if let Some(e) = source.downcast_ref::<ureq::Error>() {
+                matches!(
+                    e.kind(),
+                    ureq::ErrorKind::Dns | ureq::ErrorKind::ConnectionFailed | ureq::ErrorKind::Io
+                )
+            } else {
+                // the connection was lost while reading the body.
+                source.is::<std::io::Error>()
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Returns a random number in `0..=1`.
+#[allow(clippy::cast_precision_loss)] // both fit in the 53 bits of the mantissa.
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Returns how long to wait before the retry numbered `retry` (from 0).
+/// Half of the wait is random, so that feeds failing together are not all
+/// retried at the same time.
+fn backoff(policy: &Policy, retry: u32) -> Duration {
+    let wait = policy
+        .delay
+        .checked_mul(1 << retry.min(31))
+        .map_or(policy.max_delay, |d| d.min(policy.max_delay));
+    wait / 2 + wait.mul_f64(random() / 2.0)
+}
+
+/// Calls `attempt` until it succeeds, fails for good, or `policy` says to
+/// give up. Waits as long as asked by the server with `Retry-After`, and
+/// otherwise backs off exponentially. Never waits past `deadline`; the last
+/// failure is returned instead.
+///
+/// # Errors
+///
+/// Returns the last failure of `attempt` once it is not retried anymore.
+pub fn with_backoff<T, F>(
+    policy: &Policy,
+    deadline: Option<Instant>,
+    mut attempt: F,
+) -> Result<T, util::Error>
+where
+    F: FnMut() -> Result<T, util::Error>,
+{
+    let mut retry = 0;
+    loop {
+        let e = match attempt() {
+            Ok(v) => return Ok(v),
+            Err(e) => e,
+        };
+        if retry == policy.retries || !is_transient(&e) {
+            return Err(e);
+        }
+        let wait = match e {
+            util::Error::Http {
+                retry_after: Some(v),
+                ..
+            } => v,
+            _ => backoff(policy, retry),
+        };
+        // a wait too long to be represented is past any deadline.
+        let end = Instant::now().checked_add(wait);
+        if deadline.is_some_and(|deadline| end.is_none_or(|end| end > deadline)) {
+            return Err(e);
+        }
+        eprintln!("{}; retrying in {}s", e, wait.as_secs_f64().ceil());
+        sleep(wait);
+        retry += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unavailable(retry_after: Option<Duration>) -> util::Error {
+        util::Error::Http {
+            url: String::from("https://example.com/rss.xml"),
+            status: 503,
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn backoff_grows_within_bounds() {
+        let policy = Policy {
+            retries: 10,
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+        for retry in 0..10 {
+            let expected = Duration::from_secs(1 << retry).min(policy.max_delay);
+            let wait = backoff(&policy, retry);
+            assert!(wait >= expected / 2 && wait <= expected, "{:?}", wait);
+        }
+    }
+
+    #[test]
+    fn retries_transient_failures() {
+        let policy = Policy {
+            retries: 3,
+            delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        };
+        let mut attempts = 0;
+        let result = with_backoff(&policy, None, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(unavailable(None))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = with_backoff(&policy, None, || {
+            attempts += 1;
+            Err(util::Error::Http {
+                url: String::from("https://example.com/rss.xml"),
+                status: 404,
+                retry_after: None,
+            })
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn gives_up_at_deadline() {
+        let mut attempts = 0;
+        let result: Result<(), _> = with_backoff(
+            &Policy::default(),
+            Some(Instant::now() + Duration::from_mins(1)),
+            || {
+                attempts += 1;
+                Err(unavailable(Some(Duration::from_hours(1))))
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = with_backoff(
+            &Policy::default(),
+            Some(Instant::now() + Duration::from_mins(1)),
+            || {
+                attempts += 1;
+                Err(unavailable(Some(Duration::from_secs(u64::MAX))))
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+}
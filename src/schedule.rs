@@ -0,0 +1,152 @@
+//! Code that decides whether a feed is due to be retrieved.
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// Seconds in an hour.
+const HOUR: i64 = 3_600;
+
+/// Seconds in a day.
+const DAY: i64 = 24 * HOUR;
+
+/// The longest wait, in seconds, a feed can ask for with `<ttl>` or
+/// `sy:updatePeriod`: a month.
+pub const MAX_HINT: u64 = 30 * DAY as u64;
+
+/// The names of the days in `<skipDays>`, from the one numbered `0`.
+pub const DAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+/// When a feed says it is worth retrieving.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+pub struct Schedule {
+    /// minutes the feed may be cached for, from `<ttl>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+    /// hours (`0` to `23`, in GMT) not to retrieve the feed in, from
+    /// `<skipHours>`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_hours: Vec<u8>,
+    /// days (`0` for Monday to `6` for Sunday, in GMT) not to retrieve the
+    /// feed on, from `<skipDays>`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip_days: Vec<u8>,
+    /// seconds between two updates of the feed, from `sy:updatePeriod`
+    /// divided by `sy:updateFrequency`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_period: Option<u64>,
+}
+
+impl Schedule {
+    /// Returns whether the feed gave no hint at all.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Returns whether the feed asks not to be retrieved at `time`, in
+    /// seconds since the Unix epoch.
+    fn is_skipped(&self, time: i64) -> bool {
+        let hour = time.rem_euclid(DAY) / HOUR;
+        // 1970-01-01 was a Thursday.
+        let day = (time.div_euclid(DAY) + 3).rem_euclid(7);
+        self.skip_hours.iter().any(|&h| i64::from(h) == hour)
+            || self.skip_days.iter().any(|&d| i64::from(d) == day)
+    }
+
+    /// Returns when a feed last retrieved at `fetched` is due again, waiting
+    /// at least `min_interval` seconds. Times are in seconds since the Unix
+    /// epoch. The feed's own hints are cut to [`MAX_HINT`]. Skipped hours and
+    /// days are ignored if they cover a whole week.
+    #[must_use]
+    pub fn next_due(&self, fetched: i64, min_interval: u64) -> i64 {
+        let interval = [
+            min_interval,
+            self.ttl.unwrap_or(0).saturating_mul(60).min(MAX_HINT),
+            self.update_period.unwrap_or(0).min(MAX_HINT),
+        ]
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0);
+        let due = fetched.saturating_add(i64::try_from(interval).unwrap_or(i64::MAX));
+        let mut time = due;
+        for _ in 0..=7 * 24 {
+            if !self.is_skipped(time) {
+                return time;
+            }
+            match (time.div_euclid(HOUR) + 1).checked_mul(HOUR) {
+                Some(next) => time = next,
+                None => break,
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2019-06-03T00:00:00Z, a Monday.
+    const MONDAY: i64 = 1_559_520_000;
+
+    #[test]
+    fn interval() {
+        let schedule = Schedule {
+            ttl: Some(60),
+            ..Schedule::default()
+        };
+        assert_eq!(schedule.next_due(MONDAY, 0), MONDAY + HOUR);
+        assert_eq!(schedule.next_due(MONDAY, 7_200), MONDAY + 2 * HOUR);
+        let schedule = Schedule {
+            update_period: Some(7 * DAY as u64),
+            ..Schedule::default()
+        };
+        assert_eq!(schedule.next_due(MONDAY, 0), MONDAY + 7 * DAY);
+        assert_eq!(Schedule::default().next_due(MONDAY, 0), MONDAY);
+    }
+
+    #[test]
+    fn huge_hints() {
+        let schedule = Schedule {
+            ttl: Some(u64::MAX),
+            update_period: Some(u64::MAX),
+            ..Schedule::default()
+        };
+        assert_eq!(schedule.next_due(MONDAY, 0), MONDAY + 30 * DAY);
+        let schedule = Schedule {
+            skip_days: vec![0, 1, 2, 3, 4, 5],
+            ..Schedule::default()
+        };
+        assert_eq!(schedule.next_due(MONDAY, u64::MAX), i64::MAX);
+    }
+
+    #[test]
+    fn skipped_hours_and_days() {
+        let schedule = Schedule {
+            skip_hours: vec![0, 1, 2],
+            skip_days: vec![4, 5, 6],
+            ..Schedule::default()
+        };
+        assert_eq!(schedule.next_due(MONDAY, 0), MONDAY + 3 * HOUR);
+        // Friday 23:30 waits for Monday 03:00.
+        let friday = MONDAY + 4 * DAY + 23 * HOUR;
+        assert_eq!(
+            schedule.next_due(friday, 1_800),
+            MONDAY + 7 * DAY + 3 * HOUR
+        );
+        let always = Schedule {
+            skip_hours: (0..24).collect(),
+            ..Schedule::default()
+        };
+        assert_eq!(always.next_due(MONDAY, 60), MONDAY + 60);
+    }
+}
@@ -0,0 +1,633 @@
+//! Code that persists what `RSSt` knows about each feed between runs.
+
+use crate::metadata::{self, Collection, Metadata, Validators};
+use crate::util;
+use std::fs::copy;
+use std::path::{Path, PathBuf};
+
+/// The outcome of a single fetch of a feed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fetch {
+    /// seconds since the unix epoch when the fetch finished.
+    pub timestamp: i64,
+    /// number of new articles dumped by this fetch.
+    pub articles: usize,
+    /// the reason this fetch failed, if it did.
+    pub error: Option<String>,
+}
+
+/// A place where the state of the followed feeds is kept.
+///
+/// Methods about seen articles and fetch history are optional: backends
+/// that don't keep them may rely on the default no-op implementations.
+///
+/// Every method fails with the error met by the backend.
+#[allow(clippy::missing_errors_doc)]
+pub trait StateStore {
+    /// Returns the metadata recorded for `alias`, if any.
+    fn metadata(&self, alias: &str) -> Result<Option<Metadata>, util::Error>;
+
+    /// Records `metadata` as the newest state of the feed at `url` known as `alias`.
+    fn set_metadata(
+        &mut self,
+        alias: &str,
+        url: &str,
+        metadata: Metadata,
+    ) -> Result<(), util::Error>;
+
+    /// Returns the HTTP cache validators recorded for `alias`, if any.
+    fn validators(&self, alias: &str) -> Result<Option<Validators>, util::Error>;
+
+    /// Records `validators` as the newest HTTP cache validators of `alias`.
+    fn set_validators(&mut self, alias: &str, validators: Validators) -> Result<(), util::Error>;
+
+    /// Records that the article with `checksum` in `alias` was dumped into `path`.
+    fn record_article(
+        &mut self,
+        _alias: &str,
+        _checksum: &str,
+        _path: &Path,
+    ) -> Result<(), util::Error> {
+        Ok(())
+    }
+
+    /// Appends `fetch` to the fetch history of `alias`.
+    fn record_fetch(&mut self, _alias: &str, _fetch: &Fetch) -> Result<(), util::Error> {
+        Ok(())
+    }
+
+    /// Makes every change recorded so far persistent.
+    fn commit(&mut self) -> Result<(), util::Error>;
+}
+
+/// The name of the file used by `JsonStore` inside the metadata dir.
+pub const JSON_FILE: &str = "collections.json";
+
+/// The name of the database used by `SqliteStore` inside the metadata dir.
+pub const SQLITE_FILE: &str = "state.sqlite";
+
+/// A `StateStore` that keeps a `metadata::Collection` in a JSON file.
+pub struct JsonStore {
+    /// where the collection is saved.
+    path: PathBuf,
+    /// the collection loaded from (and to be written to) `path`.
+    collection: Collection,
+}
+
+impl JsonStore {
+    /// Loads the collection at `path`. Starts with an empty one if the file
+    /// does not exist.
+    ///
+    /// A file that can't be parsed is refused, unless `repair` is set: then
+    /// it is backed up next to `path` and every entry still readable in it
+    /// is kept.
+    ///
+    /// # Errors
+    ///
+    /// Fails with `util::Error::Corrupted` if the file can't be parsed and
+    /// `repair` is not set, or with `util::Error::TooNew` if it was written by
+    /// a newer `rsst`.
+    pub fn open(path: PathBuf, repair: bool) -> Result<Self, util::Error> {
+        let collection = match metadata::get(&path) {
+            Ok(v) => v,
+            Err(util::Error::NotFound { .. }) => Collection::default(),
+            Err(util::Error::ParseFailed { .. }) if repair => Self::repair(&path)?,
+            Err(e @ util::Error::ParseFailed { .. }) => {
+                return Err(util::Error::Corrupted {
+                    path,
+                    source: Box::new(e),
+                })
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, collection })
+    }
+
+    /// Backs up the corrupt file at `path` with a timestamp and returns the
+    /// entries salvaged from it.
+    fn repair(path: &Path) -> Result<Collection, util::Error> {
+        let text = util::to_string(path)?;
+        let backup = path.with_file_name(format!("{}.corrupt-{}", JSON_FILE, util::now()));
+        if let Err(e) = copy(path, &backup) {
+            return Err(util::Error::io(&backup, e));
+        }
+        let collection = metadata::salvage(&text);
+        eprintln!(
+            "warning: the state file {} was corrupt and has been backed up to {}; salvaged {} feed(s), the others will be dumped in full.",
+            path.display(),
+            backup.display(),
+            collection.metadata.len()
+        );
+        Ok(collection)
+    }
+}
+
+impl StateStore for JsonStore {
+    fn metadata(&self, alias: &str) -> Result<Option<Metadata>, util::Error> {
+        Ok(self.collection.metadata.get(alias).cloned())
+    }
+
+    fn set_metadata(
+        &mut self,
+        alias: &str,
+        _url: &str,
+        metadata: Metadata,
+    ) -> Result<(), util::Error> {
+        self.collection
+            .metadata
+            .insert(String::from(alias), metadata);
+        Ok(())
+    }
+
+    fn validators(&self, alias: &str) -> Result<Option<Validators>, util::Error> {
+        Ok(self.collection.validators.get(alias).cloned())
+    }
+
+    fn set_validators(&mut self, alias: &str, validators: Validators) -> Result<(), util::Error> {
+        self.collection
+            .validators
+            .insert(String::from(alias), validators);
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), util::Error> {
+        util::write_atomic(&self.path, self.collection.put()?.as_bytes())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteStore;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{Fetch, JsonStore, StateStore, JSON_FILE};
+    use crate::metadata::{Metadata, Validators};
+    use crate::schedule::Schedule;
+    use crate::util;
+    use rusqlite::{params, Connection, OptionalExtension};
+    use std::convert::TryFrom;
+    use std::fs::rename;
+    use std::path::{Path, PathBuf};
+
+    /// The schema version written into `PRAGMA user_version`.
+    const SCHEMA_VERSION: i64 = 2;
+
+    /// The statements that create the schema at `SCHEMA_VERSION`.
+    const SCHEMA: &str = "
+        CREATE TABLE IF NOT EXISTS feeds (
+            alias TEXT PRIMARY KEY,
+            url TEXT NOT NULL,
+            title TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            fetched INTEGER,
+            schedule TEXT
+        );
+        CREATE TABLE IF NOT EXISTS validators (
+            alias TEXT PRIMARY KEY,
+            etag TEXT,
+            last_modified TEXT
+        );
+        CREATE TABLE IF NOT EXISTS items (
+            alias TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            path TEXT NOT NULL,
+            PRIMARY KEY (alias, checksum)
+        );
+        CREATE TABLE IF NOT EXISTS fetches (
+            id INTEGER PRIMARY KEY,
+            alias TEXT NOT NULL,
+            fetched_at INTEGER NOT NULL,
+            articles INTEGER NOT NULL,
+            error TEXT
+        );
+    ";
+
+    /// The statements that upgrade the schema from version `1`.
+    const V1_TO_V2: &str = "
+        ALTER TABLE feeds ADD COLUMN fetched INTEGER;
+        ALTER TABLE feeds ADD COLUMN schedule TEXT;
+    ";
+
+    /// A `StateStore` backed by a `SQLite` database.
+    pub struct SqliteStore {
+        /// where the database is.
+        path: PathBuf,
+        /// the opened database.
+        conn: Connection,
+    }
+
+    impl SqliteStore {
+        /// Opens (and creates if needed) the database in `metadata_dir`.
+        /// A `collections.json` found next to a fresh database is imported
+        /// (see `JsonStore::open` for `repair`) and renamed to
+        /// `collections.json.migrated`.
+        ///
+        /// # Errors
+        ///
+        /// Fails if the database can't be opened or migrated, or was written by a
+        /// newer `rsst`.
+        pub fn open(metadata_dir: &Path, repair: bool) -> Result<Self, util::Error> {
+            let path = metadata_dir.join(super::SQLITE_FILE);
+            let conn = Connection::open(&path).map_err(|e| util::Error::StoreFailed {
+                path: PathBuf::clone(&path),
+                source: Box::new(e),
+            })?;
+            let mut store = Self { path, conn };
+            let version: i64 = store
+                .conn
+                .query_row("PRAGMA user_version", params![], |row| row.get(0))
+                .map_err(|e| store.failed(e))?;
+            if version > SCHEMA_VERSION {
+                return Err(util::Error::TooNew {
+                    path: store.path,
+                    version: u64::try_from(version).unwrap_or_default(),
+                });
+            }
+            if version < SCHEMA_VERSION {
+                // a failed upgrade leaves the database as it was.
+                store
+                    .conn
+                    .execute_batch("BEGIN")
+                    .map_err(|e| store.failed(e))?;
+                let imported = match store.upgrade(version, metadata_dir, repair) {
+                    Ok(imported) => imported,
+                    Err(e) => {
+                        let _ = store.conn.execute_batch("ROLLBACK");
+                        return Err(e);
+                    }
+                };
+                store
+                    .conn
+                    .execute_batch("COMMIT")
+                    .map_err(|e| store.failed(e))?;
+                if let Some(json) = imported {
+                    rename(&json, metadata_dir.join(format!("{JSON_FILE}.migrated")))
+                        .map_err(|e| util::Error::io(&json, e))?;
+                }
+            }
+            Ok(store)
+        }
+
+        /// Brings the schema from `version` up to `SCHEMA_VERSION`. A fresh
+        /// database is given the content of the `collections.json` in
+        /// `metadata_dir`, whose path is returned if there is one.
+        fn upgrade(
+            &mut self,
+            version: i64,
+            metadata_dir: &Path,
+            repair: bool,
+        ) -> Result<Option<PathBuf>, util::Error> {
+            if version == 1 {
+                self.conn
+                    .execute_batch(V1_TO_V2)
+                    .map_err(|e| self.failed(e))?;
+            }
+            self.conn
+                .execute_batch(SCHEMA)
+                .map_err(|e| self.failed(e))?;
+            let imported = if version == 0 {
+                self.migrate_json(metadata_dir, repair)?
+            } else {
+                None
+            };
+            self.conn
+                .pragma_update(None, "user_version", &SCHEMA_VERSION)
+                .map_err(|e| self.failed(e))?;
+            Ok(imported)
+        }
+
+        /// Imports the `collections.json` in `metadata_dir` if there is one,
+        /// and returns its path.
+        fn migrate_json(
+            &mut self,
+            metadata_dir: &Path,
+            repair: bool,
+        ) -> Result<Option<PathBuf>, util::Error> {
+            let json = metadata_dir.join(JSON_FILE);
+            if !json.exists() {
+                return Ok(None);
+            }
+            let old = JsonStore::open(json.clone(), repair)?;
+            for (alias, metadata) in &old.collection.metadata {
+                self.set_metadata(alias, "", metadata.clone())?;
+            }
+            for (alias, validators) in &old.collection.validators {
+                self.set_validators(alias, validators.clone())?;
+            }
+            Ok(Some(json))
+        }
+
+        /// Wraps a `rusqlite` failure into the crate error.
+        fn failed(&self, e: rusqlite::Error) -> util::Error {
+            util::Error::StoreFailed {
+                path: PathBuf::clone(&self.path),
+                source: Box::new(e),
+            }
+        }
+    }
+
+    impl StateStore for SqliteStore {
+        fn metadata(&self, alias: &str) -> Result<Option<Metadata>, util::Error> {
+            let row = self
+                .conn
+                .query_row(
+                    "SELECT title, checksum, fetched, schedule FROM feeds WHERE alias = ?1",
+                    params![alias],
+                    |row| {
+                        let schedule: Option<String> = row.get(3)?;
+                        Ok((
+                            Metadata {
+                                title: row.get(0)?,
+                                checksum: row.get(1)?,
+                                fetched: row.get(2)?,
+                                schedule: Schedule::default(),
+                            },
+                            schedule,
+                        ))
+                    },
+                )
+                .optional()
+                .map_err(|e| self.failed(e))?;
+            match row {
+                Some((mut metadata, Some(schedule))) => {
+                    metadata.schedule =
+                        serde_json::from_str(&schedule).map_err(|e| util::Error::StoreFailed {
+                            path: PathBuf::clone(&self.path),
+                            source: Box::new(e),
+                        })?;
+                    Ok(Some(metadata))
+                }
+                row => Ok(row.map(|(metadata, _)| metadata)),
+            }
+        }
+
+        fn set_metadata(
+            &mut self,
+            alias: &str,
+            url: &str,
+            metadata: Metadata,
+        ) -> Result<(), util::Error> {
+            let schedule = if metadata.schedule.is_empty() {
+                None
+            } else {
+                Some(serde_json::to_string(&metadata.schedule).map_err(|e| {
+                    util::Error::DumpFailed {
+                        source: Box::new(e),
+                    }
+                })?)
+            };
+            self.conn
+                .execute(
+                    "INSERT OR REPLACE INTO feeds (alias, url, title, checksum, fetched, schedule)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        alias,
+                        url,
+                        metadata.title,
+                        metadata.checksum,
+                        metadata.fetched,
+                        schedule
+                    ],
+                )
+                .map_err(|e| self.failed(e))?;
+            Ok(())
+        }
+
+        fn validators(&self, alias: &str) -> Result<Option<Validators>, util::Error> {
+            self.conn
+                .query_row(
+                    "SELECT etag, last_modified FROM validators WHERE alias = ?1",
+                    params![alias],
+                    |row| {
+                        Ok(Validators {
+                            etag: row.get(0)?,
+                            last_modified: row.get(1)?,
+                        })
+                    },
+                )
+                .optional()
+                .map_err(|e| self.failed(e))
+        }
+
+        fn set_validators(
+            &mut self,
+            alias: &str,
+            validators: Validators,
+        ) -> Result<(), util::Error> {
+            self.conn
+                .execute(
+                    "INSERT OR REPLACE INTO validators (alias, etag, last_modified)
+                     VALUES (?1, ?2, ?3)",
+                    params![alias, validators.etag, validators.last_modified],
+                )
+                .map_err(|e| self.failed(e))?;
+            Ok(())
+        }
+
+        fn record_article(
+            &mut self,
+            alias: &str,
+            checksum: &str,
+            path: &Path,
+        ) -> Result<(), util::Error> {
+            self.conn
+                .execute(
+                    "INSERT OR REPLACE INTO items (alias, checksum, path) VALUES (?1, ?2, ?3)",
+                    params![alias, checksum, path.to_string_lossy()],
+                )
+                .map_err(|e| self.failed(e))?;
+            Ok(())
+        }
+
+        fn record_fetch(&mut self, alias: &str, fetch: &Fetch) -> Result<(), util::Error> {
+            self.conn
+                .execute(
+                    "INSERT INTO fetches (alias, fetched_at, articles, error)
+                     VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        alias,
+                        fetch.timestamp,
+                        i64::try_from(fetch.articles).unwrap_or(i64::MAX),
+                        fetch.error
+                    ],
+                )
+                .map_err(|e| self.failed(e))?;
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<(), util::Error> {
+            // every statement is committed as soon as it is executed.
+            Ok(())
+        }
+    }
+}
+
+/// Opens the state store named `backend` inside `metadata_dir`.
+/// `"json"` is used if `backend` is `None`. See `JsonStore::open` for `repair`.
+///
+/// # Errors
+///
+/// Fails if `backend` is unknown or not built in, or if the store can't be
+/// opened.
+pub fn open(
+    backend: Option<&str>,
+    metadata_dir: &Path,
+    repair: bool,
+) -> Result<Box<dyn StateStore>, util::Error> {
+    match backend {
+        None | Some("json") => Ok(Box::new(JsonStore::open(
+            metadata_dir.join(JSON_FILE),
+            repair,
+        )?)),
+        #[cfg(feature = "sqlite")]
+        Some("sqlite") => Ok(Box::new(SqliteStore::open(metadata_dir, repair)?)),
+        Some(v) => Err(util::Error::NotSupported {
+            what: format!("state backend `{v}`"),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schedule::Schedule;
+    use std::env;
+    use std::fs::{read_dir, remove_dir_all, write};
+
+    fn example() -> Metadata {
+        Metadata {
+            title: String::from("hello, world"),
+            checksum: String::from("42"),
+            fetched: Some(1_559_520_000),
+            schedule: Schedule {
+                ttl: Some(60),
+                ..Schedule::default()
+            },
+        }
+    }
+
+    #[test]
+    fn json_store_round_trip() {
+        let dir = util::scratch_dir("state-json");
+        let mut store = open(None, &dir, false).unwrap();
+        assert_eq!(store.metadata("simple").unwrap(), None);
+        store
+            .set_metadata("simple", "https://example.com/rss.xml", example())
+            .unwrap();
+        store.commit().unwrap();
+        let store = open(Some("json"), &dir, false).unwrap();
+        assert_eq!(store.metadata("simple").unwrap(), Some(example()));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn json_store_repair() {
+        let dir = util::scratch_dir("state-repair");
+        let path = dir.join(JSON_FILE);
+        write(
+            &path,
+            r#"{"metadata":{"simple":{"title":"hello, world","checksum":"42","fetched":1559520000,"schedule":{"ttl":60}},"#,
+        )
+        .unwrap();
+        assert!(open(None, &dir, false).is_err());
+        let store = open(None, &dir, true).unwrap();
+        assert_eq!(store.metadata("simple").unwrap(), Some(example()));
+        let backups = read_dir(&dir)
+            .unwrap()
+            .filter(|e| {
+                let name = e.as_ref().unwrap().file_name();
+                name.to_string_lossy()
+                    .starts_with("collections.json.corrupt-")
+            })
+            .count();
+        assert_eq!(backups, 1);
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_backend() {
+        assert!(open(Some("NOT_EXISTS"), &env::temp_dir(), false).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_migrates_json() {
+        let dir = util::scratch_dir("state-sqlite");
+        let mut json = open(Some("json"), &dir, false).unwrap();
+        json.set_metadata("simple", "", example()).unwrap();
+        json.commit().unwrap();
+        let store = open(Some("sqlite"), &dir, false).unwrap();
+        assert_eq!(store.metadata("simple").unwrap(), Some(example()));
+        assert!(!dir.join(JSON_FILE).exists());
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_upgrades_v1() {
+        let dir = util::scratch_dir("state-sqlite-v1");
+        let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE)).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE feeds (
+                alias TEXT PRIMARY KEY,
+                url TEXT NOT NULL,
+                title TEXT NOT NULL,
+                checksum TEXT NOT NULL
+            );
+            INSERT INTO feeds VALUES ('simple', '', 'hello, world', '42');
+            PRAGMA user_version = 1;",
+        )
+        .unwrap();
+        drop(conn);
+        let mut store = open(Some("sqlite"), &dir, false).unwrap();
+        let metadata = store.metadata("simple").unwrap().unwrap();
+        assert_eq!(metadata.checksum, "42");
+        assert_eq!(metadata.fetched, None);
+        store.set_metadata("simple", "", example()).unwrap();
+        assert_eq!(store.metadata("simple").unwrap(), Some(example()));
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_too_new() {
+        let dir = util::scratch_dir("state-sqlite-too-new");
+        let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE)).unwrap();
+        conn.execute_batch("PRAGMA user_version = 3;").unwrap();
+        drop(conn);
+        match open(Some("sqlite"), &dir, false) {
+            Err(util::Error::TooNew { version, .. }) => assert_eq!(version, 3),
+            Err(e) => panic!("unexpected error {:?}", e),
+            Ok(_) => panic!("opened a database written by a newer rsst"),
+        }
+        remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_failed_migration() {
+        let dir = util::scratch_dir("state-sqlite-failed");
+        write(
+            dir.join(JSON_FILE),
+            r#"{"simple": {"title": "hello, world", "#,
+        )
+        .unwrap();
+        assert!(open(Some("sqlite"), &dir, false).is_err());
+        assert!(dir.join(JSON_FILE).exists());
+        let conn = rusqlite::Connection::open(dir.join(SQLITE_FILE)).unwrap();
+        let tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master",
+                rusqlite::params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 0);
+        drop(conn);
+        // the import is attempted again, this time salvaging what it can.
+        let store = open(Some("sqlite"), &dir, true).unwrap();
+        assert_eq!(store.metadata("simple").unwrap(), None);
+        assert!(!dir.join(JSON_FILE).exists());
+        remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,8 +1,20 @@
 //! Provides functions related to the the source.
 
-use crate::metadata::Metadata;
+use crate::http;
+use crate::metadata::{Metadata, Validators};
+use crate::schedule::{Schedule, DAYS, MAX_HINT};
+use crate::util;
+use html5ever::driver::ParseOpts;
+use html5ever::parse_document;
+use html5ever::rcdom::{NodeData, RcDom};
+use html5ever::tendril::TendrilSink;
 use md5;
 use rss;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use url::Url;
 
 /// A representation of an article in the feed.
 #[derive(Debug)]
@@ -82,10 +94,47 @@ impl Metadata {
         Self {
             title: String::from(ch.title()),
             checksum: format!("{:x}", md5::compute(candidate)),
+            fetched: Some(util::now()),
+            schedule: schedule(ch),
         }
     }
 }
 
+/// Reads the hints of the given `Channel` about when to retrieve it again.
+/// Hints that can't be parsed are ignored, and waits longer than
+/// [`MAX_HINT`] are cut to it.
+fn schedule(ch: &rss::Channel) -> Schedule {
+    use rss::extension::syndication::UpdatePeriod;
+    Schedule {
+        ttl: ch
+            .ttl()
+            .and_then(|v| v.trim().parse().ok())
+            .map(|ttl: u64| ttl.min(MAX_HINT / 60)),
+        skip_hours: ch
+            .skip_hours()
+            .iter()
+            .filter_map(|v| v.trim().parse().ok())
+            .filter(|&h| h < 24)
+            .collect(),
+        skip_days: ch
+            .skip_days()
+            .iter()
+            .filter_map(|v| DAYS.iter().position(|d| d.eq_ignore_ascii_case(v.trim())))
+            .map(|d| d as u8)
+            .collect(),
+        update_period: ch.syndication_ext().map(|sy| {
+            let period = match sy.period() {
+                UpdatePeriod::HOURLY => 3_600,
+                UpdatePeriod::DAILY => 86_400,
+                UpdatePeriod::WEEKLY => 7 * 86_400,
+                UpdatePeriod::MONTHLY => 30 * 86_400,
+                UpdatePeriod::YEARLY => 365 * 86_400,
+            };
+            (period / u64::from(sy.frequency().max(1))).min(MAX_HINT)
+        }),
+    }
+}
+
 /// A representation of a feed.
 #[derive(Debug)]
 pub struct Source {
@@ -93,27 +142,171 @@ pub struct Source {
     pub article: Vec<Article>,
     /// metadata about this feed.
     pub metadata: Metadata,
+    /// the HTTP cache validators returned along with this feed.
+    pub validators: Validators,
 }
 
+/// What retrieving a feed gave.
 #[derive(Debug)]
-pub enum Error {
-    /// failed to parse the given RSS feed.
-    RSSParseFailed,
+pub struct Retrieved {
+    /// the feed, or `None` if it did not change since the given validators.
+    pub source: Option<Source>,
+    /// where the feed has permanently moved to, if it did.
+    pub moved_to: Option<String>,
+    /// the feeds linked from the web page found at the address, the first of
+    /// which was read; empty if the address was a feed.
+    pub discovered: Vec<String>,
+}
+
+/// Adds `https://` to `url` if it is remote and has no scheme, as in
+/// `example.com/rss.xml`.
+#[must_use]
+pub fn normalize(url: &str) -> String {
+    match locate(url) {
+        Ok(Location::Remote) if !url.contains("://") => format!("https://{url}"),
+        _ => String::from(url),
+    }
+}
+
+/// Returns whether `url` is the address of a feed on the network, rather
+/// than a local file, a command or the standard input.
+#[must_use]
+pub fn is_remote(url: &str) -> bool {
+    matches!(locate(url), Ok(Location::Remote))
+}
+
+/// Returns whether `url` is the address of a feed served over HTTP or HTTPS,
+/// once `normalize`d.
+#[must_use]
+pub fn is_http(url: &str) -> bool {
+    is_remote(url)
+        && url::Url::parse(&normalize(url))
+            .is_ok_and(|u| u.scheme() == "http" || u.scheme() == "https")
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+/// Returns whether `body` looks like a web page rather than a feed.
+fn is_html(body: &[u8]) -> bool {
+    let start: Vec<u8> = body
+        .iter()
+        .skip_while(|b| b.is_ascii_whitespace() || **b == 0xef || **b == 0xbb || **b == 0xbf)
+        .take(14)
+        .map(u8::to_ascii_lowercase)
+        .collect();
+    start.starts_with(b"<!doctype html") || start.starts_with(b"<html")
+}
+
+/// Returns the addresses of the feeds the web page `body`, found at `url`,
+/// links to with `<link rel="alternate">`, in order, RSS ones first.
+fn discover(url: &str, body: &[u8]) -> Vec<String> {
+    let Ok(dom) = parse_document(RcDom::default(), ParseOpts::default())
+        .from_utf8()
+        .read_from(&mut &body[..])
+    else {
+        return vec![];
+    };
+    let base = Url::parse(url).ok();
+    let mut rss = vec![];
+    let mut atom = vec![];
+    let mut nodes = vec![Rc::clone(&dom.document)];
+    while let Some(node) = nodes.pop() {
+        if let NodeData::Element { name, attrs, .. } = &node.data {
+            if &*name.local == "link" {
+                let attrs = attrs.borrow();
+                let attr = |key: &str| {
+                    attrs
+                        .iter()
+                        .find(|a| &*a.name.local == key)
+                        .map(|a| a.value.trim().to_lowercase())
+                };
+                let alternate =
+                    attr("rel").is_some_and(|rel| rel.split_whitespace().any(|v| v == "alternate"));
+                let href =
+                    attrs
+                        .iter()
+                        .find(|a| &*a.name.local == "href")
+                        .and_then(|a| match &base {
+                            Some(base) => base.join(a.value.trim()).ok().map(String::from),
+                            None => Some(String::from(a.value.trim())),
+                        });
+                if let (true, Some(href)) = (alternate, href) {
+                    match attr("type").as_deref() {
+                        Some("application/rss+xml") => rss.push(href),
+                        Some("application/atom+xml") => atom.push(href),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        // pushed in reverse, so that they are visited in document order.
+        nodes.extend(node.children.borrow().iter().rev().map(Rc::clone));
     }
+    rss.extend(atom);
+    rss
+}
+
+/// Where a feed is read from.
+#[derive(Debug, PartialEq)]
+enum Location {
+    /// the standard input, given as `-`.
+    Stdin,
+    /// a local file, given as a `file://` URL or as a path.
+    File(PathBuf),
+    /// what a shell command prints, given as `exec:<command>`.
+    Command(String),
+    /// anything else, retrieved over HTTP.
+    Remote,
 }
 
-impl std::error::Error for Error {}
+/// Tells where the feed at `url` is read from. Paths must be absolute or
+/// start with `./` or `../`, so that `example.com/rss.xml` is still a URL.
+fn locate(url: &str) -> Result<Location, util::Error> {
+    if url == "-" {
+        return Ok(Location::Stdin);
+    }
+    if let Some(command) = url.strip_prefix("exec:") {
+        return Ok(Location::Command(String::from(command)));
+    }
+    if url.starts_with("file:") {
+        return match Url::parse(url).ok().and_then(|u| u.to_file_path().ok()) {
+            Some(path) => Ok(Location::File(path)),
+            None => Err(util::Error::FetchFailed {
+                url: String::from(url),
+                source: "not a valid file URL".into(),
+            }),
+        };
+    }
+    let path = Path::new(url);
+    if path.is_absolute() || url.starts_with("./") || url.starts_with("../") {
+        return Ok(Location::File(PathBuf::from(path)));
+    }
+    Ok(Location::Remote)
+}
 
-/// Try serializing the feed at the `url` into a `Source`.
-pub fn to_source(url: &str) -> Result<Source, Error> {
-    let channel = match rss::Channel::from_url(url) {
+/// Parses `body`, the feed read from `url` along with its HTTP cache
+/// `validators`, into a `Source`. If given, the shell command `filter` is
+/// fed `body` and what it prints is parsed instead.
+fn parse(
+    url: &str,
+    body: &[u8],
+    validators: Validators,
+    filter: Option<&str>,
+) -> Result<Source, util::Error> {
+    let filtered;
+    let body = match filter {
+        Some(filter) => {
+            filtered = util::shell(filter, Some(body))?;
+            &filtered[..]
+        }
+        None => body,
+    };
+    let channel = match rss::Channel::read_from(body) {
         Ok(v) => v,
-        Err(_) => return Err(Error::RSSParseFailed),
+        Err(e) => {
+            return Err(util::Error::FeedParseFailed {
+                url: String::from(url),
+                source: Box::new(e),
+            })
+        }
     };
     let metadata = Metadata::new(&channel);
     let article = channel
@@ -121,7 +314,333 @@ pub fn to_source(url: &str) -> Result<Source, Error> {
         .into_iter()
         .map(|x| Article::new(&x))
         .collect();
-    Ok(Source { metadata, article })
+    Ok(Source {
+        article,
+        metadata,
+        validators,
+    })
 }
 
-// TODO: tests
+/// Try serializing the feed at the `url` into a `Source` with `client` and
+/// `options`, passing it through `filter` first (see `parse`). If
+/// `validators` are given and the feed did not change since they were
+/// returned, gives no `Source`. `url` may also be a local file (see
+/// `locate`), `exec:<command>` to run a shell command printing the feed, or
+/// `-` to read the standard input; those are always read.
+///
+/// # Errors
+///
+/// Fails if the feed can't be retrieved or parsed.
+pub fn to_source(
+    client: &http::Client,
+    url: &str,
+    validators: Option<&Validators>,
+    options: &http::Options,
+    filter: Option<&str>,
+) -> Result<Retrieved, util::Error> {
+    let mut body = vec![];
+    match locate(url)? {
+        Location::Stdin => {
+            if let Err(e) = io::stdin().read_to_end(&mut body) {
+                return Err(util::Error::io(Path::new("<stdin>"), e));
+            }
+        }
+        Location::File(path) => match fs::read(&path) {
+            Ok(v) => body = v,
+            Err(e) => return Err(util::Error::io(&path, e)),
+        },
+        Location::Command(command) => body = util::shell(&command, None)?,
+        Location::Remote => {
+            let response = client.get(url, validators, options)?;
+            let (body, validators) = match response.content {
+                http::Content::NotModified => {
+                    return Ok(Retrieved {
+                        source: None,
+                        moved_to: response.moved_to,
+                        discovered: vec![],
+                    })
+                }
+                http::Content::Body(body, validators) => (body, validators),
+            };
+            if !is_html(&body) {
+                return Ok(Retrieved {
+                    source: Some(parse(url, &body, validators, filter)?),
+                    moved_to: response.moved_to,
+                    discovered: vec![],
+                });
+            }
+            let page = response.moved_to.as_deref().unwrap_or(url);
+            let discovered = discover(page, &body);
+            let Some(feed) = discovered.first() else {
+                return Err(util::Error::FeedParseFailed {
+                    url: String::from(url),
+                    source: "a web page that links to no feed".into(),
+                });
+            };
+            // the validators of the page would not tell whether the feed
+            // changed, so none are kept.
+            let source = match client.get(feed, None, &options.follow(url, feed))?.content {
+                http::Content::Body(body, _) => {
+                    Some(parse(feed, &body, Validators::default(), filter)?)
+                }
+                http::Content::NotModified => None,
+            };
+            return Ok(Retrieved {
+                source,
+                moved_to: response.moved_to,
+                discovered,
+            });
+        }
+    }
+    Ok(Retrieved {
+        source: Some(parse(url, &body, Validators::default(), filter)?),
+        moved_to: None,
+        discovered: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Returns the path of the fixture feed called `name`.
+    fn fixture(name: &str) -> String {
+        let path = env::current_dir()
+            .expect("failed to get current dir")
+            .join("fixtures/feeds")
+            .join(name);
+        String::from(path.to_str().expect("failed to convert to path"))
+    }
+
+    /// Reads the feed at `url`, which must not be remote.
+    fn read(url: &str) -> Result<Retrieved, util::Error> {
+        to_source(
+            &http::Client::default(),
+            url,
+            None,
+            &http::Options::default(),
+            None,
+        )
+    }
+
+    #[test]
+    fn locate_sources() {
+        assert_eq!(locate("-").unwrap(), Location::Stdin);
+        assert_eq!(
+            locate("file:///srv/feeds/rss.xml").unwrap(),
+            Location::File(PathBuf::from("/srv/feeds/rss.xml"))
+        );
+        assert_eq!(
+            locate("/srv/feeds/rss.xml").unwrap(),
+            Location::File(PathBuf::from("/srv/feeds/rss.xml"))
+        );
+        assert_eq!(
+            locate("./rss.xml").unwrap(),
+            Location::File(PathBuf::from("./rss.xml"))
+        );
+        assert_eq!(
+            locate("https://example.com/rss.xml").unwrap(),
+            Location::Remote
+        );
+        assert_eq!(locate("example.com/rss.xml").unwrap(), Location::Remote);
+        assert!(locate("file://example.com/rss.xml").is_err());
+    }
+
+    #[test]
+    fn normalize_urls() {
+        assert_eq!(
+            normalize("quinoa42.github.io/rss.xml"),
+            "https://quinoa42.github.io/rss.xml"
+        );
+        assert_eq!(
+            normalize("http://example.com/rss.xml"),
+            "http://example.com/rss.xml"
+        );
+        assert_eq!(normalize("./rss.xml"), "./rss.xml");
+        assert_eq!(normalize("exec:scraper"), "exec:scraper");
+        assert_eq!(normalize("-"), "-");
+    }
+
+    /// Answers one connection per item of `bodies` on a local port with a
+    /// `200 OK`. Returns the address of the server and a handle to the
+    /// requests received.
+    fn serve(bodies: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = vec![];
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8(request).unwrap());
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn discovered_feed_credentials() {
+        let feed = fs::read_to_string(fixture("example.xml")).unwrap();
+        let page = |href: &str| {
+            format!(
+                r#"<!DOCTYPE html><html><head><link rel="alternate" type="application/rss+xml" href="{href}"></head></html>"#
+            )
+        };
+        let (elsewhere, elsewhere_handle) = serve(vec![String::clone(&feed)]);
+        let (address, handle) = serve(vec![
+            page(&format!("{elsewhere}/rss.xml")),
+            page("/rss.xml"),
+            feed,
+        ]);
+        let options = http::Options {
+            headers: vec![(String::from("Authorization"), http::basic_auth("a", "b"))],
+            ..http::Options::default()
+        };
+        let url = format!("{address}/blog/");
+        let client = http::Client::default();
+        for _ in 0..2 {
+            let retrieved = to_source(&client, &url, None, &options, None).unwrap();
+            assert_eq!(retrieved.source.unwrap().metadata.title, "Example");
+        }
+        let requests = handle.join().unwrap();
+        assert!(requests
+            .iter()
+            .all(|r| r.contains("Authorization: Basic YTpi")));
+        // the port differs, so the first feed is on another server.
+        let discovered = &elsewhere_handle.join().unwrap()[0];
+        assert!(discovered.starts_with("GET /rss.xml "));
+        assert!(!discovered.contains("Authorization"));
+    }
+
+    #[test]
+    fn discover_feeds() {
+        let page = br#"<!DOCTYPE html>
+            <html><head>
+            <link rel="stylesheet" href="/style.css">
+            <link rel="alternate" type="application/atom+xml" href="/atom.xml">
+            <link rel="Alternate" type="application/rss+xml" href="/rss.xml">
+            <link rel="alternate" type="application/rss+xml" href="https://feeds.example.org/comments.xml">
+            </head><body><p>hello</p></body></html>"#;
+        assert!(is_html(page));
+        assert!(!is_html(b"<?xml version=\"1.0\"?><rss></rss>"));
+        assert_eq!(
+            discover("https://example.com/blog/", page),
+            vec![
+                "https://example.com/rss.xml",
+                "https://feeds.example.org/comments.xml",
+                "https://example.com/atom.xml",
+            ]
+        );
+        assert!(discover("https://example.com/", b"<html><body></body></html>").is_empty());
+    }
+
+    #[test]
+    fn read_file() {
+        let path = fixture("example.xml");
+        let url = String::from(Url::from_file_path(&path).unwrap());
+        for url in &[url, path] {
+            let retrieved = read(url).unwrap();
+            assert_eq!(retrieved.moved_to, None);
+            let source = retrieved.source.unwrap();
+            assert_eq!(source.metadata.title, "Example");
+            assert_eq!(source.validators, Validators::default());
+            assert_eq!(source.article.len(), 2);
+            let article = &source.article[0];
+            assert_eq!(article.title, "Second post");
+            assert_eq!(article.link, "https://example.com/second");
+            assert_eq!(article.author, "someone@example.com");
+            assert_eq!(article.category, vec![String::from("news")]);
+            assert_eq!(article.content, "The second post.");
+            assert_eq!(source.metadata.checksum, article.checksum);
+        }
+    }
+
+    #[test]
+    fn read_schedule() {
+        let source = read(&fixture("example.xml")).unwrap().source.unwrap();
+        let schedule = source.metadata.schedule;
+        assert_eq!(schedule.ttl, Some(60));
+        assert_eq!(schedule.skip_hours, vec![0, 1]);
+        assert_eq!(schedule.skip_days, vec![6]);
+        assert_eq!(schedule.update_period, Some(7 * 86_400 / 2));
+    }
+
+    #[test]
+    fn huge_schedule() {
+        let channel = rss::Channel::read_from(
+            &br#"<rss version="2.0" xmlns:sy="http://purl.org/rss/1.0/modules/syndication/">
+<channel><title>t</title><link>l</link><description>d</description>
+<ttl>18446744073709551615</ttl><sy:updatePeriod>yearly</sy:updatePeriod>
+</channel></rss>"#[..],
+        )
+        .unwrap();
+        let schedule = schedule(&channel);
+        assert_eq!(schedule.ttl, Some(MAX_HINT / 60));
+        assert_eq!(schedule.update_period, Some(MAX_HINT));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_command() {
+        let source = read(&format!("exec:cat '{}'", fixture("example.xml")))
+            .unwrap()
+            .source
+            .unwrap();
+        assert_eq!(source.metadata.title, "Example");
+        match read("exec:echo 'no feed here' >&2; exit 3") {
+            Err(e @ util::Error::CommandFailed { .. }) => {
+                assert!(e.to_string().contains("no feed here"));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_filtered() {
+        let broken = fixture("broken.xml");
+        let filter = "cat; echo '</channel></rss>'";
+        let source = to_source(
+            &http::Client::default(),
+            &broken,
+            None,
+            &http::Options::default(),
+            Some(filter),
+        )
+        .unwrap()
+        .source
+        .unwrap();
+        assert_eq!(source.metadata.title, "Broken");
+        assert!(source.article.is_empty());
+    }
+
+    #[test]
+    fn read_errors() {
+        match read(&fixture("NOT_EXISTS.xml")) {
+            Err(util::Error::NotFound { .. }) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+        match read(&fixture("broken.xml")) {
+            Err(util::Error::FeedParseFailed { .. }) => (),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
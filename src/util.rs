@@ -1,29 +1,175 @@
 //! Misc utility functions.
 
+use std::convert::TryFrom;
 use std::env;
+use std::ffi::OsString;
 use std::fs;
-use std::path::PathBuf;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The underlying error that caused an `Error`.
+pub type Cause = Box<dyn std::error::Error + Send + Sync>;
 
 /// Error commonly used across this crate.
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub enum Error {
-    /// Not run on a supported OS.
-    NotSupported,
-    /// Didn't find the given resource.
-    NotFound,
-    /// Failed to parse some input.
-    ParseFailed,
-    /// Failed to dump some object.
-    DumpFailed,
+    /// Neither the relevant XDG variable nor `$HOME` is set.
+    NoHome,
+    /// Asked for something this build does not support.
+    NotSupported {
+        /// what is not supported, such as ``"state backend `foo`"``.
+        what: String,
+    },
+    /// Didn't find the file at `path`.
+    NotFound { path: PathBuf },
+    /// Failed to read or write the file at `path`.
+    Io { path: PathBuf, source: io::Error },
+    /// Failed to parse the file at `path`. `line` and `column` start at 1.
+    ParseFailed {
+        path: PathBuf,
+        line: Option<usize>,
+        column: Option<usize>,
+        message: String,
+        source: Option<Cause>,
+    },
+    /// The state file at `path` can't be parsed; `--repair` may salvage it.
+    Corrupted { path: PathBuf, source: Box<Error> },
+    /// The state file at `path` was written in a newer `version` of the format.
+    TooNew { path: PathBuf, version: u64 },
+    /// Failed to serialize some object.
+    DumpFailed { source: Cause },
+    /// Failed to read from or write to the state store at `path`.
+    StoreFailed { path: PathBuf, source: Cause },
+    /// Another run, whose PID is `pid` if known, holds the lock at `path`.
+    Locked { path: PathBuf, pid: Option<u32> },
+    /// Failed to retrieve `url`.
+    FetchFailed { url: String, source: Cause },
+    /// The server of `url` answered with the HTTP `status`, and asked to
+    /// wait for `retry_after` before trying again if it said so.
+    Http {
+        url: String,
+        status: u16,
+        retry_after: Option<Duration>,
+    },
+    /// Failed to parse the feed retrieved from `url`.
+    FeedParseFailed { url: String, source: Cause },
+    /// Failed to read the secret described as `what`.
+    SecretFailed { what: String, source: Cause },
+    /// The shell `command` could not be run or did not succeed.
+    CommandFailed { command: String, source: Cause },
+    /// The environment variable `name`, used in the setting `path`, is not
+    /// set.
+    UndefinedVariable { name: String, path: String },
+    /// The config file has several `problems`.
+    Invalid { problems: Vec<Error> },
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::NoHome => write!(f, "neither the XDG directories nor $HOME are set"),
+            Error::NotSupported { what } => write!(f, "{what} is not supported"),
+            Error::NotFound { path } => write!(f, "{}: no such file", path.display()),
+            Error::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::ParseFailed {
+                path,
+                line,
+                column,
+                message,
+                ..
+            } => {
+                write!(f, "{}", path.display())?;
+                if let Some(line) = line {
+                    write!(f, ":{line}")?;
+                    if let Some(column) = column {
+                        write!(f, ":{column}")?;
+                    }
+                }
+                write!(f, ": {message}")
+            }
+            Error::Corrupted { source, .. } => write!(
+                f,
+                "{source}; run again with --repair to back it up and salvage what can still be read"
+            ),
+            Error::TooNew { path, version } => write!(
+                f,
+                "{}: written in version {} of the format by a newer rsst",
+                path.display(),
+                version
+            ),
+            Error::DumpFailed { source } => write!(f, "failed to serialize: {source}"),
+            Error::StoreFailed { path, source } => write!(f, "{}: {}", path.display(), source),
+            Error::Locked { path, pid } => {
+                write!(f, "another rsst is running")?;
+                if let Some(pid) = pid {
+                    write!(f, " (pid {pid})")?;
+                }
+                write!(f, " and holds {}", path.display())
+            }
+            Error::FetchFailed { url, source } => {
+                write!(f, "failed to retrieve {url}: {source}")
+            }
+            Error::Http { url, status, .. } => write!(f, "{url}: the server answered {status}"),
+            Error::FeedParseFailed { url, source } => {
+                write!(f, "{url}: not a valid feed: {source}")
+            }
+            Error::SecretFailed { what, source } => {
+                write!(f, "failed to read {what}: {source}")
+            }
+            Error::CommandFailed { command, source } => write!(f, "`{command}`: {source}"),
+            Error::UndefinedVariable { name, path } => {
+                write!(f, "`{path}`: the environment variable `{name}` is not set")
+            }
+            Error::Invalid { problems } => {
+                write!(f, "{} problems in the config file:", problems.len())?;
+                for problem in problems {
+                    write!(f, "\n{problem}")?;
+                }
+                Ok(())
+            }
+        }
     }
 }
 
-impl std::error::Error for Error {}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Corrupted { source, .. } => Some(source.as_ref()),
+            Error::ParseFailed {
+                source: Some(source),
+                ..
+            }
+            | Error::DumpFailed { source }
+            | Error::StoreFailed { source, .. }
+            | Error::FetchFailed { source, .. }
+            | Error::FeedParseFailed { source, .. }
+            | Error::SecretFailed { source, .. }
+            | Error::CommandFailed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl Error {
+    /// Wraps the `source` that occurred when accessing the file at `path`.
+    #[must_use]
+    pub fn io(path: &Path, source: io::Error) -> Self {
+        if source.kind() == io::ErrorKind::NotFound {
+            Error::NotFound {
+                path: PathBuf::from(path),
+            }
+        } else {
+            Error::Io {
+                path: PathBuf::from(path),
+                source,
+            }
+        }
+    }
+}
 
 /// Get the xdg dir usually saved in `$var`. defaults to `$HOME/path_from_home`.
 fn get_xdg_dir(var: &str, path_from_home: &str) -> Result<PathBuf, Error> {
@@ -31,17 +177,165 @@ fn get_xdg_dir(var: &str, path_from_home: &str) -> Result<PathBuf, Error> {
         Ok(path) => PathBuf::from(path),
         Err(_) => PathBuf::from(match env::var("HOME") {
             Ok(v) => v,
-            Err(_) => return Err(Error::NotSupported),
+            Err(_) => return Err(Error::NoHome),
         })
         .join(path_from_home),
     })
 }
 
+/// Expands a leading `~` into `$HOME`, and `$VAR` or `${VAR}` into the value
+/// of the environment variable `VAR`, in `path`. Fails if a variable is not
+/// set.
+///
+/// # Errors
+///
+/// Fails with `Error::UndefinedVariable` if a variable is not set.
+pub fn expand(path: &str) -> Result<PathBuf, Error> {
+    let var = |name: &str| match env::var_os(name) {
+        Some(v) => Ok(v),
+        None => Err(Error::UndefinedVariable {
+            name: String::from(name),
+            path: String::from(path),
+        }),
+    };
+    let mut expanded = OsString::new();
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        expanded.push(env::var_os("HOME").ok_or(Error::NoHome)?);
+        rest = &rest[1..];
+    }
+    while let Some(i) = rest.find('$') {
+        expanded.push(&rest[..i]);
+        rest = &rest[i + 1..];
+        if rest.starts_with('{') {
+            if let Some(end) = rest.find('}') {
+                expanded.push(var(&rest[1..end])?);
+                rest = &rest[end + 1..];
+                continue;
+            }
+        }
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if end == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            expanded.push("$");
+        } else {
+            expanded.push(var(&rest[..end])?);
+            rest = &rest[end..];
+        }
+    }
+    expanded.push(rest);
+    Ok(PathBuf::from(expanded))
+}
+
+/// Returns the seconds elapsed since the unix epoch.
+#[must_use]
+pub fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(v) => i64::try_from(v.as_secs()).unwrap_or(i64::MAX),
+        Err(_) => 0,
+    }
+}
+
+/// Runs `command` with the shell, feeding it `input` if given, and returns
+/// what it printed. Fails if it could not be run or did not exit successfully.
+///
+/// # Errors
+///
+/// Fails with the status and what the command printed on its standard error.
+pub fn shell(command: &str, input: Option<&[u8]>) -> Result<Vec<u8>, Error> {
+    let failed = |source: Cause| Error::CommandFailed {
+        command: String::from(command),
+        source,
+    };
+    let mut process = if cfg!(windows) {
+        let mut process = Command::new("cmd");
+        process.arg("/C").arg(command);
+        process
+    } else {
+        let mut process = Command::new("sh");
+        process.arg("-c").arg(command);
+        process
+    };
+    let mut child = process
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| failed(Box::new(e)))?;
+    // written from another thread, lest both ends wait for each other.
+    let writer = match (child.stdin.take(), input) {
+        (Some(mut stdin), Some(input)) => {
+            let input = input.to_vec();
+            Some(thread::spawn(move || stdin.write_all(&input)))
+        }
+        _ => None,
+    };
+    let output = child.wait_with_output().map_err(|e| failed(Box::new(e)))?;
+    if let Some(Ok(Err(e))) = writer.map(thread::JoinHandle::join) {
+        // a command may well stop reading early, which breaks the pipe.
+        if e.kind() != io::ErrorKind::BrokenPipe {
+            return Err(failed(Box::new(e)));
+        }
+    }
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let mut message = output.status.to_string();
+        if !stderr.trim().is_empty() {
+            message = format!("{}: {}", message, stderr.trim());
+        }
+        return Err(failed(message.into()));
+    }
+    Ok(output.stdout)
+}
+
 /// Try loading the content at the given `filepath` into a `String`.
-pub fn to_string(filepath: PathBuf) -> Result<String, Error> {
+pub fn to_string(filepath: &Path) -> Result<String, Error> {
     match fs::read_to_string(filepath) {
         Ok(v) => Ok(v),
-        Err(_) => Err(Error::NotFound),
+        Err(e) => Err(Error::io(filepath, e)),
+    }
+}
+
+/// Creates the dir at `path` along with all of its missing parents.
+///
+/// # Errors
+///
+/// Fails if a dir can't be created.
+pub fn create_dir_all(path: &Path) -> Result<(), Error> {
+    fs::create_dir_all(path).map_err(|e| Error::io(path, e))
+}
+
+/// Writes `contents` into a temporary file next to `path`, then renames it
+/// to `path`, so readers only ever see the old or the new content.
+///
+/// # Errors
+///
+/// Fails if the temporary file can't be written or renamed.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let name = match path.file_name() {
+        Some(v) => v.to_string_lossy(),
+        None => {
+            return Err(Error::Io {
+                path: PathBuf::from(path),
+                source: io::Error::new(io::ErrorKind::InvalidInput, "not a file name"),
+            })
+        }
+    };
+    let tmp = path.with_file_name(format!(".{}.{}.tmp", name, std::process::id()));
+    let written = fs::File::create(&tmp)
+        .and_then(|mut f| f.write_all(contents).and_then(|()| f.sync_all()))
+        .and_then(|()| fs::rename(&tmp, path));
+    match written {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(Error::io(path, e))
+        }
     }
 }
 
@@ -58,7 +352,7 @@ pub fn get_config_file(config_dir_path: Option<String>) -> Result<PathBuf, Error
 pub fn get_metadata_dir(meta_dir_path: Option<String>) -> Result<PathBuf, Error> {
     let metadata_dir = match meta_dir_path {
         None => get_xdg_dir("XDG_DATA_HOME", ".local/share")?,
-        Some(fp) => PathBuf::from(fp),
+        Some(fp) => expand(&fp)?,
     }
     .join("rsst");
     Ok(metadata_dir)
@@ -68,6 +362,71 @@ pub fn get_metadata_dir(meta_dir_path: Option<String>) -> Result<PathBuf, Error>
 pub fn get_output_dir(output_dir_path: Option<String>) -> Result<PathBuf, Error> {
     match output_dir_path {
         None => get_xdg_dir("RSST_FOLDER", "rsst"),
-        Some(fp) => Ok(PathBuf::from(fp)),
+        Some(fp) => expand(&fp),
+    }
+}
+
+/// Returns an empty directory private to the test called `name`.
+///
+/// # Panics
+///
+/// Panics if the directory can't be created.
+#[cfg(test)]
+#[must_use]
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rsst-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("failed to create scratch dir");
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_atomic_replaces() {
+        let dir = scratch_dir("util-write-atomic");
+        let path = dir.join("article.html");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        // a failed write leaves neither the file nor its temporary copy.
+        let missing = dir.join("missing").join("article.html");
+        assert!(write_atomic(&missing, b"new").is_err());
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["article.html"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn expand_paths() {
+        env::set_var("RSST_TEST_DIR", "/srv/rsst");
+        env::remove_var("RSST_TEST_UNSET");
+        let home = env::var("HOME").unwrap();
+        assert_eq!(expand("~").unwrap(), PathBuf::from(&home));
+        assert_eq!(
+            expand("~/rsst/").unwrap(),
+            PathBuf::from(format!("{home}/rsst/"))
+        );
+        assert_eq!(expand("/srv/~me").unwrap(), PathBuf::from("/srv/~me"));
+        assert_eq!(
+            expand("$RSST_TEST_DIR/feeds").unwrap(),
+            PathBuf::from("/srv/rsst/feeds")
+        );
+        assert_eq!(
+            expand("${RSST_TEST_DIR}s/$5/a$").unwrap(),
+            PathBuf::from("/srv/rssts/$5/a$")
+        );
+        match expand("${RSST_TEST_UNSET}/rsst").unwrap_err() {
+            Error::UndefinedVariable { name, path } => {
+                assert_eq!(name, "RSST_TEST_UNSET");
+                assert_eq!(path, "${RSST_TEST_UNSET}/rsst");
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
     }
 }
@@ -0,0 +1,509 @@
+//! Code that checks the config file and points at its problems.
+
+use crate::upstream;
+use crate::util;
+use std::path::{Path, PathBuf};
+use toml::value::Table;
+use toml::Value;
+
+/// The keys allowed at the top of the config file.
+const ROOT_KEYS: &[&str] = &["include", "setting", "source"];
+
+/// The keys allowed in `[setting]`.
+const SETTING_KEYS: &[&str] = &[
+    "output_format",
+    "output_dir",
+    "metadata_dir",
+    "state_backend",
+    "concurrency",
+    "per_host_concurrency",
+    "connect_timeout",
+    "read_timeout",
+    "max_redirects",
+    "rewrite_redirects",
+    "retries",
+    "retry_delay",
+    "max_retry_delay",
+    "max_run_time",
+    "min_interval",
+    "user_agent",
+    "proxy",
+    "nest_by_group",
+];
+
+/// The settings holding a path.
+const PATH_KEYS: &[&str] = &["output_dir", "metadata_dir"];
+
+/// The keys allowed in the table of a source.
+const SOURCE_KEYS: &[&str] = &[
+    "url",
+    "cmd",
+    "filter",
+    "retries",
+    "retry_delay",
+    "max_retry_delay",
+    "min_interval",
+    "user_agent",
+    "headers",
+    "basic_auth",
+    "bearer_token",
+    "proxy",
+    "group",
+    "format",
+    "output_dir",
+    "enabled",
+    "tags",
+];
+
+/// The formats articles can be written in.
+const FORMATS: &[&str] = &["html"];
+
+/// The keys allowed in `basic_auth`.
+const BASIC_AUTH_KEYS: &[&str] = &["username", "password"];
+
+/// The keys allowed in a secret given as a table.
+const SECRET_KEYS: &[&str] = &["env", "command"];
+
+/// Splits the dotted `key` into its parts, unquoted.
+fn split_key(key: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut quote = None;
+    for c in key.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => part.push(c),
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '.') => parts.push(std::mem::take(&mut part)),
+            (None, c) if !c.is_whitespace() => part.push(c),
+            (None, _) => (),
+        }
+    }
+    parts.push(part);
+    parts
+}
+
+/// Returns the byte offset of `key` in `line`, searched from `from`, where it
+/// is a key of an inline table, i.e. right after `{` or `,` and right before
+/// `=` or `.`.
+fn find_inline(line: &str, from: usize, key: &str) -> Option<usize> {
+    let mut start = from;
+    while let Some(i) = line[start..].find(key) {
+        let at = start + i;
+        let before = line[..at].trim_end().chars().last();
+        let after = line[at + key.len()..].trim_start().chars().next();
+        let quoted = line[..at].ends_with('"') && line[at + key.len()..].starts_with('"');
+        if (before == Some('{') || before == Some(',') || quoted)
+            && (after == Some('=') || after == Some('.') || quoted)
+        {
+            return Some(if quoted { at - 1 } else { at });
+        }
+        start = at + key.len();
+    }
+    None
+}
+
+/// Returns the 1-based line and column of every place in `text` that
+/// defines the key at `path`, or a table or key nested in it.
+#[must_use]
+pub fn locate(text: &str, path: &[&str]) -> Vec<(usize, usize)> {
+    let mut found = vec![];
+    let mut table: Vec<String> = vec![];
+    // whether the current table is already found as a whole.
+    let mut within = false;
+    let mut multiline = false;
+    for (n, line) in text.lines().enumerate() {
+        let toggles = line.matches("\"\"\"").count() + line.matches("'''").count();
+        if multiline {
+            multiline = toggles % 2 == 0;
+            continue;
+        }
+        multiline = toggles % 2 == 1;
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        let column = line[..indent].chars().count() + 1;
+        if trimmed.starts_with('[') {
+            let inner = trimmed.trim_start_matches('[');
+            table = split_key(&inner[..inner.find(']').unwrap_or(inner.len())]);
+            within = table.len() >= path.len() && table.iter().zip(path).all(|(a, b)| a == b);
+            if within {
+                found.push((n + 1, column));
+            }
+            continue;
+        }
+        let eq = match trimmed.find('=') {
+            Some(eq) if !within && !trimmed.starts_with('#') => eq,
+            _ => continue,
+        };
+        let mut key = table.clone();
+        key.extend(split_key(&trimmed[..eq]));
+        let shared = key.iter().zip(path).take_while(|(a, b)| a == b).count();
+        if shared == path.len() {
+            found.push((n + 1, column));
+        } else if shared == key.len() {
+            // the rest of `path` may be in an inline table.
+            let mut at = Some(indent + eq + 1);
+            for part in &path[shared..] {
+                at = at.and_then(|from| find_inline(line, from, part));
+            }
+            if let Some(at) = at {
+                found.push((n + 1, line[..at].chars().count() + 1));
+            }
+        }
+    }
+    found
+}
+
+/// Returns the number of single character edits turning `a` into `b`.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (row[j + 1] + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != cb));
+            diagonal = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+/// Returns the key in `known` that `key` was most likely meant to be, if
+/// any is close enough.
+fn suggest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    let limit = (key.chars().count() / 3).max(1);
+    known
+        .iter()
+        .map(|k| (distance(key, k), *k))
+        .filter(|(d, _)| *d <= limit)
+        .min()
+        .map(|(_, k)| k)
+}
+
+/// Collects the problems found in the config file at `path`.
+struct Checker<'a> {
+    /// the path of the config file.
+    path: &'a Path,
+    /// the content of the config file.
+    text: &'a str,
+    /// the problems found so far.
+    problems: Vec<util::Error>,
+}
+
+impl Checker<'_> {
+    /// Records the problem described by `message` about the key at `path`.
+    fn report(&mut self, path: &[&str], message: String) {
+        let problem = problem_at(self.path, self.text, path, message);
+        self.problems.push(problem);
+    }
+
+    /// Reports the keys of `table`, found at `path`, that are not `known`.
+    fn keys(&mut self, path: &[&str], table: &Table, known: &[&str]) {
+        for key in table.keys() {
+            if known.contains(&key.as_str()) {
+                continue;
+            }
+            let within = match path {
+                [] => String::new(),
+                ["setting"] => String::from(" in [setting]"),
+                ["source", alias] => format!(" in source `{alias}`"),
+                _ => format!(" in {}", path.join(".")),
+            };
+            let mut message = format!("unknown key `{key}`{within}");
+            if path.is_empty() && SETTING_KEYS.contains(&key.as_str()) {
+                message.push_str("; did you mean to put it in [setting]?");
+            } else if let Some(known) = suggest(key, known) {
+                message = format!("{message}; did you mean `{known}`?");
+            }
+            let mut at = path.to_vec();
+            at.push(key);
+            self.report(&at, message);
+        }
+    }
+
+    /// Checks the secret at `path`, given either as a string or as a table.
+    fn secret(&mut self, path: &[&str], value: &Value) {
+        if let Value::Table(table) = value {
+            self.keys(path, table, SECRET_KEYS);
+            if table.len() != 1 {
+                let message = format!("{} needs exactly one of `env` or `command`", path.join("."));
+                self.report(path, message);
+            }
+        }
+    }
+
+    /// Checks the `[setting]` section.
+    fn setting(&mut self, setting: &Table) {
+        self.keys(&["setting"], setting, SETTING_KEYS);
+        if let Some(Value::String(format)) = setting.get("output_format") {
+            self.format(&["setting", "output_format"], format);
+        }
+        for key in PATH_KEYS {
+            if let Some(Value::String(dir)) = setting.get(*key) {
+                if let Err(e) = util::expand(dir) {
+                    self.report(&["setting", key], e.to_string());
+                }
+            }
+        }
+    }
+
+    /// Reports `format`, found at `path`, if articles can't be written in it.
+    fn format(&mut self, path: &[&str], format: &str) {
+        if FORMATS.contains(&format) {
+            return;
+        }
+        let known: Vec<_> = FORMATS.iter().map(|f| format!("`{f}`")).collect();
+        let message = format!(
+            "unsupported format `{format}`; expected {}",
+            known.join(" or ")
+        );
+        self.report(path, message);
+    }
+
+    /// Checks the address `url` of the source known as `alias`, found at
+    /// `path`.
+    fn url(&mut self, path: &[&str], alias: &str, url: &str) {
+        if !upstream::is_remote(url) {
+            return;
+        }
+        let message = match url::Url::parse(&upstream::normalize(url)) {
+            Ok(parsed) => match parsed.scheme() {
+                "http" | "https" => return,
+                scheme => format!("source `{alias}`: unsupported scheme `{scheme}` in `{url}`"),
+            },
+            Err(e) => format!("source `{alias}`: invalid address `{url}`: {e}"),
+        };
+        self.report(path, message);
+    }
+
+    /// Checks the source known as `alias`.
+    fn source(&mut self, alias: &str, value: &Value) {
+        let table = match value {
+            Value::String(url) => return self.url(&["source", alias], alias, url),
+            Value::Table(table) => table,
+            _ => {
+                let message = format!("source `{alias}` should be an address or a table");
+                return self.report(&["source", alias], message);
+            }
+        };
+        self.keys(&["source", alias], table, SOURCE_KEYS);
+        match (table.get("url"), table.get("cmd")) {
+            (Some(Value::String(url)), None) => self.url(&["source", alias, "url"], alias, url),
+            (Some(_), Some(_)) => {
+                let message = format!("source `{alias}` can't have both `url` and `cmd`");
+                self.report(&["source", alias, "cmd"], message);
+            }
+            (None, None) => {
+                let message = format!("source `{alias}` needs either `url` or `cmd`");
+                self.report(&["source", alias], message);
+            }
+            _ => (),
+        }
+        if let Some(Value::String(dir)) = table.get("output_dir") {
+            if let Err(e) = util::expand(dir) {
+                self.report(&["source", alias, "output_dir"], e.to_string());
+            }
+        }
+        if let Some(Value::String(format)) = table.get("format") {
+            self.format(&["source", alias, "format"], format);
+        }
+        if let Some(Value::Table(auth)) = table.get("basic_auth") {
+            self.keys(&["source", alias, "basic_auth"], auth, BASIC_AUTH_KEYS);
+            if let Some(password) = auth.get("password") {
+                self.secret(&["source", alias, "basic_auth", "password"], password);
+            }
+        }
+        if let Some(token) = table.get("bearer_token") {
+            self.secret(&["source", alias, "bearer_token"], token);
+            if table.contains_key("basic_auth") {
+                let message =
+                    format!("source `{alias}` can't have both `basic_auth` and `bearer_token`");
+                self.report(&["source", alias, "bearer_token"], message);
+            }
+        }
+        if let Some(Value::Table(headers)) = table.get("headers") {
+            for (name, value) in headers {
+                self.secret(&["source", alias, "headers", name], value);
+            }
+        }
+    }
+}
+
+/// Builds the problem described by `message` about `key` in the config file
+/// at `path`, whose content is `text`.
+#[must_use]
+pub fn problem_at(path: &Path, text: &str, key: &[&str], message: String) -> util::Error {
+    let position = locate(text, key).first().copied();
+    util::Error::ParseFailed {
+        path: PathBuf::from(path),
+        line: position.map(|(line, _)| line),
+        column: position.map(|(_, column)| column),
+        message,
+        source: None,
+    }
+}
+
+/// Returns every problem found in `value`, parsed from `text`, the content
+/// of the config file at `path`, in the order they appear in the file.
+/// Problems of types are left to deserialization.
+pub fn check(path: &Path, text: &str, value: &Value) -> Vec<util::Error> {
+    let mut checker = Checker {
+        path,
+        text,
+        problems: vec![],
+    };
+    if let Value::Table(root) = value {
+        checker.keys(&[], root, ROOT_KEYS);
+        match root.get("include") {
+            Some(Value::Array(patterns)) if patterns.iter().all(Value::is_str) => (),
+            Some(_) => {
+                let message = String::from("`include` should be a list of paths");
+                checker.report(&["include"], message);
+            }
+            None => (),
+        }
+        if let Some(Value::Table(setting)) = root.get("setting") {
+            checker.setting(setting);
+        }
+        if let Some(Value::Table(sources)) = root.get("source") {
+            for (alias, source) in sources {
+                checker.source(alias, source);
+            }
+        }
+    }
+    let mut problems = checker.problems;
+    problems.sort_by_key(|p| match p {
+        util::Error::ParseFailed { line, column, .. } => (line.unwrap_or(0), column.unwrap_or(0)),
+        _ => (0, 0),
+    });
+    problems
+}
+
+/// Returns where the error `message` of `toml`, which gave no position,
+/// happened in `text`, along with a clearer message if there is one.
+pub fn position(text: &str, message: &str) -> (Option<(usize, usize)>, Option<String>) {
+    // e.g. "duplicate key: `a` for key `source`".
+    let quoted: Vec<&str> = message.split('`').skip(1).step_by(2).collect();
+    let nested = message.contains(" for key `");
+    let within = match quoted.last() {
+        Some(key) if nested => split_key(key),
+        _ => vec![],
+    };
+    let mut path: Vec<&str> = within.iter().map(String::as_str).collect();
+    if message.starts_with("duplicate key: ") && quoted.len() > usize::from(nested) {
+        path.push(quoted[0]);
+        let found = locate(text, &path);
+        if let [first, .., again] = found.as_slice() {
+            let what = match path.as_slice() {
+                ["source", alias] => format!("source `{alias}`"),
+                _ => format!("`{}`", path.join(".")),
+            };
+            let message = format!(
+                "{} is defined again; first defined at line {}",
+                what, first.0
+            );
+            return (Some(*again), Some(message));
+        }
+        path.pop();
+    }
+    (locate(text, &path).first().copied(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = r#"[setting]
+ouput_dir = "/srv/rsst"
+
+[source]
+plain = "https://example.com/rss.xml"
+ftp = "ftp://example.com/rss.xml"
+flaky = { url = "https://example.org/rss.xml", retrys = 5 }
+
+[source.secret]
+url = "https://example.net/rss.xml"
+basic_auth = { username = "me", password = { env = "PASS", comand = "pass" } }
+bearer_token = "token"
+"#;
+
+    #[test]
+    fn locate_keys() {
+        assert_eq!(locate(TEXT, &["setting"]), vec![(1, 1)]);
+        assert_eq!(locate(TEXT, &["setting", "ouput_dir"]), vec![(2, 1)]);
+        assert_eq!(locate(TEXT, &["source", "flaky", "retrys"]), vec![(7, 48)]);
+        assert_eq!(locate(TEXT, &["source", "secret"]), vec![(9, 1)]);
+        assert_eq!(
+            locate(
+                TEXT,
+                &["source", "secret", "basic_auth", "password", "comand"]
+            ),
+            vec![(11, 60)]
+        );
+        assert_eq!(locate(TEXT, &["source", "missing"]), vec![]);
+    }
+
+    #[test]
+    fn suggest_keys() {
+        assert_eq!(suggest("ouput_dir", SETTING_KEYS), Some("output_dir"));
+        assert_eq!(suggest("retrys", SOURCE_KEYS), Some("retries"));
+        assert_eq!(suggest("color", SOURCE_KEYS), None);
+    }
+
+    #[test]
+    fn check_every_problem() {
+        let value: Value = toml::from_str(TEXT).unwrap();
+        let problems: Vec<_> = check(Path::new("config.toml"), TEXT, &value)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "config.toml:2:1: unknown key `ouput_dir` in [setting]; did you mean `output_dir`?",
+                "config.toml:6:1: source `ftp`: unsupported scheme `ftp` in `ftp://example.com/rss.xml`",
+                "config.toml:7:48: unknown key `retrys` in source `flaky`; did you mean `retries`?",
+                "config.toml:11:33: source.secret.basic_auth.password needs exactly one of `env` or `command`",
+                "config.toml:11:60: unknown key `comand` in source.secret.basic_auth.password; did you mean `command`?",
+                "config.toml:12:1: source `secret` can't have both `basic_auth` and `bearer_token`",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_formats() {
+        let text = "[setting]\noutput_format = \"pdf\"\n\n[source]\nok = { url = \"https://a.org/rss\", format = \"html\" }\nbad = { url = \"https://b.org/rss\", format = \"epub\" }\n";
+        let value: Value = toml::from_str(text).unwrap();
+        let problems: Vec<_> = check(Path::new("config.toml"), text, &value)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                "config.toml:2:1: unsupported format `pdf`; expected `html`",
+                "config.toml:6:36: unsupported format `epub`; expected `html`",
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_alias() {
+        let text =
+            "[source]\na = \"https://a.org/rss\"\n\n[source.a]\nurl = \"https://b.org/rss\"\n";
+        let e = toml::from_str::<Value>(text).unwrap_err();
+        assert_eq!(
+            position(text, &e.to_string()),
+            (
+                Some((4, 1)),
+                Some(String::from(
+                    "source `a` is defined again; first defined at line 2"
+                ))
+            )
+        );
+    }
+}